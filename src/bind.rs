@@ -0,0 +1,55 @@
+use std::io;
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::time::Duration;
+
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+
+
+/// Open a TCP connection to `addr` from the local address `local` (any port), set by `--bind-address`.
+///
+/// std can not bind a socket before connecting it, so this goes through socket2.
+pub(crate) fn connect_from(local: IpAddr, addr: SocketAddr, timeout: Option<Duration>) -> io::Result<TcpStream> {
+    if local.is_ipv4() != addr.is_ipv4() {
+        return Err(io::Error::new(
+            io::ErrorKind::AddrNotAvailable,
+            format!("{} can not be reached from the bind address {}", addr, local),
+        ));
+    }
+
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    socket.bind(&SockAddr::from(SocketAddr::new(local, 0)))?;
+
+    match timeout {
+        Some(timeout) => socket.connect_timeout(&SockAddr::from(addr), timeout)?,
+        None => socket.connect(&SockAddr::from(addr))?,
+    }
+
+    Ok(socket.into())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr, TcpListener};
+
+    #[test]
+    fn test_connect_from_loopback() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let stream = connect_from(IpAddr::V4(Ipv4Addr::LOCALHOST), addr, Some(Duration::from_secs(5))).unwrap();
+        let (_, peer) = listener.accept().unwrap();
+
+        assert_eq!(stream.local_addr().unwrap().ip(), IpAddr::V4(Ipv4Addr::LOCALHOST));
+        assert_eq!(peer, stream.local_addr().unwrap());
+    }
+
+    #[test]
+    fn test_connect_from_other_family() {
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 9);
+
+        let err = connect_from(IpAddr::V6(Ipv6Addr::LOCALHOST), addr, None).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrNotAvailable);
+    }
+}
@@ -0,0 +1,2141 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{IsTerminal, Read, Write, Seek};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use zeroize::{Zeroize, Zeroizing};
+use base64::{
+    engine::general_purpose,
+    prelude::*
+};
+use libcold;
+use serde::Serialize;
+use ureq::tls::RootCerts;
+
+use crate::cli::{OutputFormat, PresetServerUrl, ServerUrl, clean_server_url};
+use crate::consts;
+use crate::contacts;
+use crate::crypto;
+use crate::doh::DohResolver;
+use crate::error::{Error, exit_code};
+use crate::json;
+use crate::metrics;
+use crate::requests;
+use crate::shutdown;
+use crate::state;
+use crate::trace;
+use crate::utils;
+
+
+/// `Serialize` only writes the options the user gave, for `--print-config --format json`. Key material
+/// and account state are skipped and passwords are redacted.
+#[derive(Zeroize, Serialize)]
+#[zeroize(drop)]
+pub struct Config {
+    #[serde(skip)]
+    pub(crate) server_url: Option<ServerUrl>,
+
+    /// Server URLs from the command line / config file, tried in order when creating a new state file.
+    pub(crate) server_urls: Vec<PresetServerUrl>,
+    /// Port of the `server_urls` (and prompted URLs) that don't name one (`--default-server-port`).
+    #[zeroize(skip)]
+    pub(crate) default_server_port: Option<u16>,
+    /// From `--ca-file`, the roots in it are trusted for https servers in addition to the Mozilla roots.
+    pub(crate) ca_file: Option<String>,
+    #[zeroize(skip)]
+    #[serde(skip)]
+    pub(crate) ca_certs: Option<RootCerts>,
+    /// From `--doh`, the DNS-over-HTTPS endpoint used for direct connections.
+    pub(crate) doh_url: Option<String>,
+    #[zeroize(skip)]
+    #[serde(skip)]
+    pub(crate) doh: Option<DohResolver>,
+    /// SHA-256 hashes of the server's accepted public keys (`--pin-sha256`), empty means plain CA validation.
+    #[zeroize(skip)]
+    #[serde(skip)]
+    pub(crate) pins: Vec<[u8; 32]>,
+
+    #[serde(skip)]
+    pub(crate) user_id: Option<Zeroizing<String>>,
+    #[serde(skip)]
+    pub(crate) auth_token: Option<Zeroizing<String>>,
+
+    #[serde(skip)]
+    pub(crate) auth_secret_key: Option<Zeroizing<Vec<u8>>>,
+    #[serde(skip)]
+    pub(crate) auth_public_key: Option<Zeroizing<Vec<u8>>>,
+
+
+    #[serde(skip)]
+    pub(crate) contact_list: Option<Vec<libcold::Contact>>,
+    /// Messages the relay was unreachable for, sent by `flush_queue` and saved with the state file.
+    #[serde(skip)]
+    pub(crate) queue: state::OutboundQueue,
+    /// From `--queue-max`, 0 turns queueing off.
+    pub(crate) queue_max: usize,
+
+    pub(crate) state_file_path: Option<Zeroizing<String>>,
+    /// Held while the state file is in use so a second instance can't clobber it.
+    #[zeroize(skip)]
+    #[serde(skip)]
+    pub(crate) state_lock: Option<utils::StateLock>,
+    /// From `--log-file`, opened by `open_log_file` once the options are known to be valid.
+    pub(crate) log_file_path: Option<Zeroizing<String>>,
+    #[zeroize(skip)]
+    #[serde(skip)]
+    pub(crate) log_file: Option<utils::LogFile>,
+    /// From `--profile-dir`, already folded into the two paths above unless they were given too.
+    pub(crate) profile_dir: Option<String>,
+    /// Ignore a held state file lock (`--force`), for recovery.
+    pub(crate) force: bool,
+    /// From `--state-pass-file`, used instead of prompting for the state file password.
+    #[serde(serialize_with = "json::serialize_redacted")]
+    pub(crate) state_file_password: Option<Zeroizing<String>>,
+    pub(crate) proxy: Option<requests::ProxyInfo>,
+    /// Where `proxy` came from, for `--print-effective-proxy`.
+    #[zeroize(skip)]
+    pub(crate) proxy_source: requests::ProxySource,
+    /// Set by `--isolate-circuits`, gives every server and contact its own SOCKS credentials.
+    #[serde(skip)]
+    pub(crate) circuit_isolation: Option<requests::CircuitIsolation>,
+    /// Hosts connected to directly: `--proxy-bypass` for any proxy, plus NO_PROXY for a proxy taken from
+    /// HTTPS_PROXY / ALL_PROXY.
+    pub(crate) no_proxy_hosts: Vec<String>,
+    /// Send loopback servers through the proxy too (`--proxy-loopback`) instead of connecting directly.
+    pub(crate) proxy_loopback: bool,
+    /// 0 by default, raised by `-v` up to `consts::VERBOSITY_DEBUG` (`--debug`).
+    pub(crate) verbosity: u8,
+    /// Write the `tracing` spans as JSON lines (`--trace-json`), see `start_tracing`.
+    pub(crate) trace_json: bool,
+    /// Refuse plaintext http:// servers, see `ServerUrl::is_plaintext`.
+    pub(crate) require_tls: bool,
+    /// Refuse every server that is not a .onion address (`--onion-only`), validated with the proxy in `build`.
+    pub(crate) onion_only: bool,
+    /// Accept any server certificate (`--insecure-skip-verify`), only for testing against a self-signed relay.
+    pub(crate) insecure_skip_verify: bool,
+    /// Sent as the `User-Agent` header of every request (`--user-agent`).
+    pub(crate) user_agent: String,
+    /// Local address of direct connections (`--bind-address`), rejected together with a proxy.
+    #[zeroize(skip)]
+    pub(crate) bind_address: Option<IpAddr>,
+    /// Applies to every connect, proxy handshake and request.
+    #[zeroize(skip)]
+    #[serde(serialize_with = "json::serialize_secs")]
+    pub(crate) timeout: Duration,
+    /// Largest response body read from the server (`--max-response-bytes`), per response.
+    pub(crate) max_response_bytes: u64,
+    /// Retries of the first connection at startup (`--connect-retries`), see `with_connect_retries`.
+    pub(crate) connect_retries: u32,
+    /// Longest time state changes wait in memory before `autosave` writes them (`--autosave-secs`),
+    /// zero writes on every change.
+    #[zeroize(skip)]
+    #[serde(serialize_with = "json::serialize_secs")]
+    pub(crate) autosave: Duration,
+    /// Set by `mark_state_changed`, cleared by `save_state_file`.
+    #[serde(skip)]
+    pub(crate) state_dirty: bool,
+    #[zeroize(skip)]
+    #[serde(skip)]
+    pub(crate) last_save: Option<Instant>,
+    /// Backoff between reconnect attempts, see `with_reconnect`.
+    #[zeroize(skip)]
+    pub(crate) reconnect: requests::ReconnectPolicy,
+    /// Refuse to load a state file readable by group or others instead of only warning.
+    pub(crate) strict_perms: bool,
+    /// Every warning that goes through `warn_or_fail` is an error instead (`--strict`).
+    pub(crate) strict: bool,
+    /// Encoding the state file is written in (`--state-format`), reading detects it from the file.
+    #[zeroize(skip)]
+    pub(crate) state_format: state::StateFormat,
+    /// Oldest TLS version accepted from https servers (`--min-tls`).
+    #[zeroize(skip)]
+    pub(crate) min_tls: requests::TlsVersion,
+    /// Serve health and Prometheus metrics on this address (`--metrics-addr`), see `start_metrics`.
+    #[zeroize(skip)]
+    pub(crate) metrics_addr: Option<SocketAddr>,
+    /// Only run the `--check` connectivity test.
+    pub(crate) check: bool,
+    /// Only run the `bench-proxy` latency benchmark with this many connections (`--samples`).
+    pub(crate) bench: Option<u32>,
+    /// Only summarize the state file (`inspect`).
+    pub(crate) inspect: bool,
+    /// Only destroy the state file (`wipe`).
+    pub(crate) wipe: bool,
+    /// Only write the contacts to this file (`export-contacts <path>`).
+    pub(crate) export_contacts: Option<String>,
+    /// Only merge the contacts of this file into the state file (`import-contacts <path>`).
+    pub(crate) import_contacts: Option<String>,
+    /// Skip the confirmation prompt of `wipe` (`--yes`).
+    pub(crate) assume_yes: bool,
+    /// Only validate the options and print them (`--dry-run`).
+    pub(crate) dry_run: bool,
+    /// Only print the proxy and its source (`--print-effective-proxy`).
+    #[serde(skip)]
+    pub(crate) print_effective_proxy: bool,
+    /// Print the addresses the server resolves to before anything else (`--show-resolved-address`).
+    #[serde(skip)]
+    pub(crate) show_resolved_address: bool,
+    /// Suppress the status summary on success (`--quiet`), errors and debug output are unaffected.
+    pub(crate) quiet: bool,
+    /// Format of the `--print-config` output.
+    #[zeroize(skip)]
+    #[serde(skip)]
+    pub(crate) output_format: OutputFormat,
+    /// How main prints errors (`--error-format`).
+    #[zeroize(skip)]
+    #[serde(skip)]
+    pub(crate) error_format: OutputFormat,
+
+    #[serde(skip)]
+    pub(crate) state_file_password_hash: Option<Zeroizing<Vec<u8>>>,
+    #[serde(skip)]
+    pub(crate) state_file_password_hash_salt: Option<Zeroizing<Vec<u8>>>
+}
+
+
+
+// Written by hand so key material, tokens and passwords never end up in debug output or logs.
+impl fmt::Debug for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Config")
+            .field("server_url", &self.server_url.as_ref().map(|u| u.to_string()))
+            .field("server_urls", &self.server_urls.iter().map(|u| u.url.to_string()).collect::<Vec<String>>())
+            .field("default_server_port", &self.default_server_port)
+            .field("ca_file", &self.ca_file)
+            .field("doh_url", &self.doh_url)
+            .field("pins", &self.pins.len())
+            .field("user_id", &self.user_id.as_deref().map(|u| u.as_str()))
+            .field("auth_token", &self.auth_token.as_ref().map(|_| "***"))
+            .field("auth_secret_key", &self.auth_secret_key.as_ref().map(|_| "***"))
+            .field("auth_public_key", &self.auth_public_key.as_ref().map(|k| k.len()))
+            .field("contact_list", &self.contact_list.as_ref().map(|c| c.len()))
+            .field("queue", &self.queue.len())
+            .field("queue_max", &self.queue_max)
+            .field("state_file_path", &self.state_file_path.as_deref().map(|p| p.as_str()))
+            .field("state_file_password", &self.state_file_password.as_ref().map(|_| "***"))
+            .field("log_file_path", &self.log_file_path.as_deref().map(|p| p.as_str()))
+            .field("log_file", &self.log_file.is_some())
+            .field("profile_dir", &self.profile_dir)
+            .field("state_lock", &self.state_lock.is_some())
+            .field("force", &self.force)
+            .field("proxy", &self.proxy)
+            .field("proxy_source", &self.proxy_source)
+            .field("circuit_isolation", &self.circuit_isolation.is_some())
+            .field("no_proxy_hosts", &self.no_proxy_hosts)
+            .field("proxy_loopback", &self.proxy_loopback)
+            .field("verbosity", &self.verbosity)
+            .field("trace_json", &self.trace_json)
+            .field("require_tls", &self.require_tls)
+            .field("onion_only", &self.onion_only)
+            .field("insecure_skip_verify", &self.insecure_skip_verify)
+            .field("user_agent", &self.user_agent)
+            .field("bind_address", &self.bind_address)
+            .field("timeout", &self.timeout)
+            .field("max_response_bytes", &self.max_response_bytes)
+            .field("connect_retries", &self.connect_retries)
+            .field("autosave", &self.autosave)
+            .field("state_dirty", &self.state_dirty)
+            .field("last_save", &self.last_save)
+            .field("reconnect", &self.reconnect)
+            .field("strict_perms", &self.strict_perms)
+            .field("strict", &self.strict)
+            .field("state_format", &self.state_format)
+            .field("min_tls", &self.min_tls)
+            .field("metrics_addr", &self.metrics_addr)
+            .field("check", &self.check)
+            .field("bench", &self.bench)
+            .field("inspect", &self.inspect)
+            .field("wipe", &self.wipe)
+            .field("export_contacts", &self.export_contacts)
+            .field("import_contacts", &self.import_contacts)
+            .field("assume_yes", &self.assume_yes)
+            .field("dry_run", &self.dry_run)
+            .field("print_effective_proxy", &self.print_effective_proxy)
+            .field("show_resolved_address", &self.show_resolved_address)
+            .field("quiet", &self.quiet)
+            .field("output_format", &self.output_format)
+            .field("error_format", &self.error_format)
+            .field("state_file_password_hash", &self.state_file_password_hash.as_ref().map(|_| "***"))
+            .field("state_file_password_hash_salt", &self.state_file_password_hash_salt.as_ref().map(|_| "***"))
+            .finish()
+    }
+}
+
+
+impl Config {
+    /// True when `check` / `--check` was given and only the connectivity test should run.
+    pub fn check_requested(&self) -> bool {
+        self.check
+    }
+
+    /// The number of connections to time when `bench-proxy` / `--test-proxy-latency` was given.
+    pub fn bench_samples(&self) -> Option<u32> {
+        self.bench
+    }
+
+    /// True when the `inspect` subcommand was given and the state file should only be summarized.
+    pub fn inspect_requested(&self) -> bool {
+        self.inspect
+    }
+
+    /// True when the `wipe` subcommand was given and the state file should be destroyed.
+    pub fn wipe_requested(&self) -> bool {
+        self.wipe
+    }
+
+    /// True when `export-contacts` was given and only the contacts should be exported.
+    pub fn export_contacts_requested(&self) -> bool {
+        self.export_contacts.is_some()
+    }
+
+    /// True when `import-contacts` was given and only the contacts of a file should be merged in.
+    pub fn import_contacts_requested(&self) -> bool {
+        self.import_contacts.is_some()
+    }
+
+    /// True when `--print-effective-proxy` was given and only the resolved proxy should be printed.
+    pub fn print_effective_proxy_requested(&self) -> bool {
+        self.print_effective_proxy
+    }
+
+    /// True when `--show-resolved-address` was given, see `check::resolved_addresses`.
+    pub fn show_resolved_address_requested(&self) -> bool {
+        self.show_resolved_address
+    }
+
+    /// The proxy every request goes through (credentials redacted) and where it came from.
+    pub fn effective_proxy(&self) -> String {
+        let mut out = match &self.proxy {
+            Some(proxy) => format!("proxy: {}\n", describe_proxy(proxy)),
+            None => String::from("proxy: none\n"),
+        };
+        out.push_str(&format!("source: {}\n", self.proxy_source));
+
+        if self.proxy.is_some() && !self.no_proxy_hosts.is_empty() {
+            out.push_str(&format!("no proxy for: {}\n", self.no_proxy_hosts.join(", ")));
+        }
+
+        out
+    }
+
+    /// True when `--dry-run` was given and the resolved options should only be printed.
+    pub fn dry_run_requested(&self) -> bool {
+        self.dry_run
+    }
+
+    /// The resolved options as printed by `--dry-run` / `--print-config`, in the requested `--format`.
+    pub fn print_config(&self) -> Result<String, Error> {
+        match self.output_format {
+            OutputFormat::Human => Ok(self.dry_run_summary()),
+            OutputFormat::Json => serde_json::to_string_pretty(self).map_err(|_| Error::FailedToSerializeConfig),
+        }
+    }
+
+    /// The resolved options as printed by `--dry-run`, with proxy credentials redacted.
+    fn dry_run_summary(&self) -> String {
+        let mut out = String::new();
+
+        if self.server_urls.is_empty() {
+            out.push_str("server: (none, prompted when creating a state file)\n");
+        }
+        for url in &self.server_urls {
+            out.push_str(&format!("server: {}\n", url.url));
+        }
+        if let Some(port) = self.default_server_port {
+            out.push_str(&format!("default server port: {}\n", port));
+        }
+
+        if let Some(p) = &self.ca_file {
+            out.push_str(&format!("ca file: {}\n", p));
+        }
+        if let Some(u) = &self.doh_url {
+            out.push_str(&format!("doh: {}\n", u));
+        }
+        if let Some(ip) = &self.bind_address {
+            out.push_str(&format!("bind address: {}\n", ip));
+        }
+        for pin in &self.pins {
+            out.push_str(&format!("pin: sha256/{}\n", BASE64_STANDARD.encode(pin)));
+        }
+
+        if let Some(dir) = &self.profile_dir {
+            out.push_str(&format!("profile dir: {}\n", dir));
+        }
+        match &self.state_file_path {
+            Some(p) => out.push_str(&format!("state file: {}\n", p.as_str())),
+            None => out.push_str("state file: (none, prompted at startup)\n"),
+        }
+        if self.state_file_password.is_some() {
+            out.push_str("state file password: *** (from file)\n");
+        }
+        if let Some(p) = &self.log_file_path {
+            out.push_str(&format!("log file: {}\n", p.as_str()));
+        }
+
+        match &self.proxy {
+            Some(proxy) => out.push_str(&format!("proxy: {}\n", describe_proxy(proxy))),
+            None => out.push_str("proxy: none\n"),
+        }
+        if self.circuit_isolation.is_some() {
+            out.push_str("isolate circuits: true\n");
+        }
+        if !self.no_proxy_hosts.is_empty() {
+            out.push_str(&format!("no proxy for: {}\n", self.no_proxy_hosts.join(", ")));
+        }
+        if self.proxy_loopback {
+            out.push_str("proxy loopback: true\n");
+        }
+
+        out.push_str(&format!("user agent: {}\n", self.user_agent));
+        out.push_str(&format!("timeout: {}s\n", self.timeout.as_secs()));
+        out.push_str(&format!("max response bytes: {}\n", self.max_response_bytes));
+        out.push_str(&format!("connect retries: {}\n", self.connect_retries));
+        if self.autosave.is_zero() {
+            out.push_str("autosave: on every change\n");
+        } else {
+            out.push_str(&format!("autosave: {}s\n", self.autosave.as_secs()));
+        }
+        out.push_str(&format!(
+            "reconnect: {}ms base, {}ms max, {}\n",
+            self.reconnect.base_ms,
+            self.reconnect.max_ms,
+            self.reconnect.max_retries.map(|n| format!("{} retries", n)).unwrap_or_else(|| String::from("unlimited retries"))
+        ));
+        out.push_str(&format!("queue max: {}\n", self.queue_max));
+        out.push_str(&format!("require tls: {}\n", self.require_tls));
+        out.push_str(&format!("min tls: {}\n", self.min_tls));
+        if self.onion_only {
+            out.push_str("onion only: true\n");
+        }
+        if self.insecure_skip_verify {
+            out.push_str("insecure skip verify: true (server certificates are NOT checked)\n");
+        }
+        out.push_str(&format!("strict perms: {}\n", self.strict_perms));
+        if self.strict {
+            out.push_str("strict: true (security warnings are errors)\n");
+        }
+        out.push_str(&format!("state format: {}\n", self.state_format));
+        if let Some(addr) = &self.metrics_addr {
+            out.push_str(&format!("metrics: http://{}/metrics\n", addr));
+        }
+        out.push_str(&format!("force: {}\n", self.force));
+        if self.trace_json {
+            out.push_str("trace json: true\n");
+        }
+        out.push_str(&format!("verbosity: {}", self.verbosity));
+
+        out
+    }
+
+    /// Open the `--log-file`, if any, and record the resolved options in it (secrets redacted).
+    pub fn open_log_file(&mut self) -> Result<(), Error> {
+        if let Some(path) = &self.log_file_path {
+            self.log_file = Some(utils::open_log_file(path)?);
+
+            self.log_to_file("INFO", &format!("coldwire-desktop {} starting", env!("CARGO_PKG_VERSION")));
+            for line in self.dry_run_summary().lines() {
+                self.log_to_file("INFO", &format!("config {}", line));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Install the subscriber for the connection spans of `-vv` and `--trace-json`, see `trace::init`.
+    /// Call after `open_log_file` so they go to the log file too.
+    pub fn start_tracing(&self) {
+        let log_file = match &self.log_file {
+            Some(log) => match log.try_clone() {
+                Ok(file) => Some(file),
+                // never fall back to the terminal the --log-file was meant to keep quiet
+                Err(_) => {
+                    log.write_line("WARN", "Failed to share the log file with the connection tracing, it is off");
+                    return;
+                }
+            },
+            None => None,
+        };
+
+        trace::init(self.verbosity, self.trace_json, log_file);
+    }
+
+    /// Write a line to the `--log-file` only, nothing is printed when there is none.
+    pub(crate) fn log_to_file(&self, level: &str, message: &str) {
+        if let Some(log) = &self.log_file {
+            log.write_line(level, message);
+        }
+    }
+
+    /// A diagnostic shown from verbosity `level` up, to the `--log-file` if there is one, otherwise on stdout.
+    pub(crate) fn log_info(&self, level: u8, message: &str) {
+        if self.verbosity < level {
+            return;
+        }
+
+        match &self.log_file {
+            Some(log) => log.write_line("INFO", message),
+            None => println!("[*] {}", message),
+        }
+    }
+
+    /// A warning, to the `--log-file` if there is one, otherwise on stderr.
+    pub(crate) fn log_warning(&self, message: &str) {
+        match &self.log_file {
+            Some(log) => log.write_line("WARN", message),
+            None => eprintln!("WARNING: {}", message),
+        }
+    }
+
+    /// A security warning, or under `--strict` an error: `error` is returned for the caller to report, like
+    /// any other error, and `message` only goes to the `--log-file`.
+    pub(crate) fn warn_or_fail(&self, message: &str, error: Error) -> Result<(), Error> {
+        if self.strict {
+            self.log_to_file("ERROR", &format!("{} Refusing to continue with --strict.", message));
+            return Err(error);
+        }
+
+        self.log_warning(message);
+        Ok(())
+    }
+
+    /// Print the `--insecure-skip-verify` warning, in red on a terminal. It goes to stderr even with a
+    /// `--log-file` so it can't be missed.
+    pub fn warn_if_insecure(&self) {
+        if !self.insecure_skip_verify {
+            return;
+        }
+
+        let message = "--insecure-skip-verify is set: server certificates are NOT verified. Anyone on the network path \
+            can impersonate the relay, read your traffic and tamper with it. Only use this against a test relay.";
+
+        if std::io::stderr().is_terminal() {
+            eprintln!("\x1b[1;31mWARNING: {}\x1b[0m", message);
+        } else {
+            eprintln!("WARNING: {}", message);
+        }
+        self.log_to_file("WARN", message);
+    }
+
+    /// Persist the state file and release its lock before exiting on SIGINT / SIGTERM.
+    ///
+    /// Safe to call more than once, later calls find nothing left to do.
+    pub fn shutdown(&mut self) -> Result<(), Error> {
+        if self.state_lock.is_none() {
+            return Ok(());
+        }
+
+        self.log_to_file("INFO", "shutting down");
+
+        if self.state_file_password_hash.is_some() {
+            self.save_state_file()?;
+        }
+        self.state_lock = None;
+
+        Ok(())
+    }
+
+    /// Run a request (`op`), retrying it with jittered exponential backoff while it fails with a transient
+    /// error (`Error::is_transient`) and `idempotency` says it is safe to send again. A busy relay's
+    /// `Retry-After` replaces the backoff, see `requests::ReconnectPolicy::backoff_delay`.
+    ///
+    /// A `NonIdempotent` request runs once, see `requests::Idempotency`. Any other error is returned right
+    /// away, as is the last one once `--reconnect-max-retries` is used up or a shutdown was requested.
+    pub fn with_reconnect<T, F: FnMut(&mut Config) -> Result<T, Error>>(&mut self, idempotency: requests::Idempotency, op: F) -> Result<T, Error> {
+        let policy = self.reconnect.clone();
+        self.retry(&policy, idempotency, op)
+    }
+
+    /// Run the first connection at startup (`op`), retrying it `--connect-retries` times while the server
+    /// or proxy can't be reached.
+    ///
+    /// Only the number of attempts comes from the options, the backoff is the default of `--reconnect-*` so
+    /// the two stay independent. `op` is run again from the start, so it has to be idempotent like the
+    /// authentication handshake.
+    pub fn with_connect_retries<T, F: FnMut(&mut Config) -> Result<T, Error>>(&mut self, op: F) -> Result<T, Error> {
+        let policy = requests::ReconnectPolicy {
+            max_retries: Some(self.connect_retries),
+            ..requests::ReconnectPolicy::default()
+        };
+        self.retry(&policy, requests::Idempotency::Idempotent, op)
+    }
+
+    fn retry<T, F: FnMut(&mut Config) -> Result<T, Error>>(&mut self, policy: &requests::ReconnectPolicy, idempotency: requests::Idempotency, mut op: F) -> Result<T, Error> {
+        let mut attempt = 0;
+
+        loop {
+            match op(self) {
+                Err(e) if e.is_transient()
+                    && idempotency == requests::Idempotency::Idempotent
+                    && policy.should_retry(attempt)
+                    && !shutdown::requested() => {
+                    let delay = policy.backoff_delay(attempt, e.retry_after());
+                    metrics::record_reconnect();
+                    match e {
+                        Error::ServerBusy(Some(_)) => self.log_warning(&format!("The server is busy, retrying in {} ms as it asked (attempt {}).", delay.as_millis(), attempt + 1)),
+                        Error::ServerBusy(None) => self.log_warning(&format!("The server is busy, retrying in {} ms (attempt {}).", delay.as_millis(), attempt + 1)),
+                        _ => self.log_warning(&format!("Could not reach the server, reconnecting in {} ms (attempt {}).", delay.as_millis(), attempt + 1)),
+                    }
+
+                    std::thread::sleep(delay);
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Start serving `/metrics` and `/health` on `--metrics-addr` in the background, if it was given.
+    pub fn start_metrics(&self) -> Result<(), Error> {
+        let Some(addr) = self.metrics_addr else {
+            return Ok(());
+        };
+
+        let addr = metrics::serve(addr)?;
+        metrics::record_queue_depth(self.queue.len());
+
+        if !self.quiet {
+            println!("[*] Serving metrics on http://{}/metrics", addr);
+        }
+        self.log_to_file("INFO", &format!("serving metrics on {}", addr));
+
+        Ok(())
+    }
+
+    /// How errors should be printed, from `--error-format`.
+    pub fn error_format(&self) -> OutputFormat {
+        self.error_format
+    }
+
+    /// True when `--quiet` was given and only errors and debug output should be printed.
+    pub fn is_quiet(&self) -> bool {
+        self.quiet
+    }
+
+    pub fn user_id(&self) -> Option<&str> {
+        self.user_id.as_deref().map(|u| u.as_str())
+    }
+
+
+    pub fn confirm_proxy_info(&mut self) -> Result<(), Error> {
+        if let Some(proxy) = &self.proxy {
+            let user_part = proxy.username
+                .as_ref()
+                .map(|u| format!(" ({})", u.as_str()))
+                .unwrap_or_default();
+
+            let pass_part = proxy.password
+                .as_ref()
+                .map(|_| " (with password authentication)".to_string())
+                .unwrap_or_default();
+
+            println!(
+                "Configured proxy: {:?} {}:{}{}{}\n",
+                proxy.proxy_type,
+                proxy.host,
+                proxy.port,
+                user_part,
+                pass_part
+            );
+        } else {
+            println!("No proxy was configured.\n");
+        }
+
+
+        let confirm = prompt_user("Is the proxy configuration correct? [y/N]: ", true)?;
+        if !confirm.eq_ignore_ascii_case("yes") && !confirm.eq_ignore_ascii_case("y") {
+            println!("Aborting the program for safety.");
+            std::process::exit(exit_code::ABORTED);
+        }
+
+        Ok(())
+    }
+
+
+    pub fn prompt_state_file(&mut self) -> Result<(), Error> {
+        let mut state_file_path = Zeroizing::new(String::new());
+        
+
+        if let Some(path) = self.state_file_path.take() {
+            state_file_path = path;
+        }
+
+        while state_file_path.is_empty() {
+            state_file_path = prompt_user(
+                "Enter the state file path (If it does not exist, it will be created): ",
+                true
+            )?;
+            if state_file_path.is_empty() {
+                println!("Please enter a valid path!\n");
+            } else if let Err(reason) = utils::check_state_file_path(&state_file_path) {
+                println!("That path {}, please enter the path of a file!\n", reason);
+                state_file_path = Zeroizing::new(String::new());
+            }
+        }
+
+        if Path::new(&state_file_path).exists() {
+            self.lock_state_file(&state_file_path)?;
+
+            if let Some(mode) = utils::overly_permissive_mode(&state_file_path)? {
+                if self.strict_perms {
+                    eprintln!("ERROR: State file {} has mode {:o}, refusing to continue with --strict-perms. Run: chmod 600 {}", state_file_path.as_str(), mode, state_file_path.as_str());
+                    return Err(Error::StateFilePermissionsTooOpen);
+                }
+                self.warn_or_fail(
+                    &format!("State file {} has mode {:o}, other users may be able to read your keys. Run: chmod 600 {}", state_file_path.as_str(), mode, state_file_path.as_str()),
+                    Error::StateFilePermissionsTooOpen,
+                )?;
+            }
+
+            let migrated = self.prompt_and_decrypt_state_file(&state_file_path)?;
+            self.state_file_path = Some(state_file_path);
+
+            if migrated {
+                self.save_state_file()?;
+            }
+
+        } else {
+            let confirm = prompt_user(&format!("State file {} does not exist, would you like to create it? [y/N]: ", state_file_path.as_str()), true)?;
+            if !confirm.eq_ignore_ascii_case("yes") && !confirm.eq_ignore_ascii_case("y") {
+                println!("Aborting program.");
+                std::process::exit(exit_code::ABORTED);
+            }
+
+            self.update_server_url()?;
+
+            loop {
+                let (state_file_password, state_file_password_confirm) = match self.state_file_password.take() {
+                    Some(p) => (p.clone(), p),
+                    None => (prompt_user("Create password: ", false)?, prompt_user("Confirm password: ", false)?),
+                };
+                
+                if state_file_password != state_file_password_confirm {
+                    println!("Password does not match! Try again.\n");
+                    continue;
+                }
+            
+                let state_file_password_salt = libcold::crypto::generate_secure_random_bytes_whiten(consts::ARGON2ID_SALT_SIZE)
+                    .map_err(|_| Error::FailedToGenerateSecureRandomBytes)?;
+
+
+                let state_file_password_hash = libcold::crypto::hash_argon2id(state_file_password.as_bytes(), &state_file_password_salt)
+                .map_err(|_| Error::Argon2IdHashingError)?;
+     
+                let state_file_password_hash = Zeroizing::new(state_file_password_hash[..32].to_vec());
+
+
+                self.state_file_password_hash = Some(state_file_password_hash);
+                self.state_file_password_hash_salt = Some(state_file_password_salt);
+
+                utils::create_parent_dirs(&state_file_path)?;
+                self.lock_state_file(&state_file_path)?;
+                self.state_file_path = Some(state_file_path);
+
+                self.save_state_file()?;
+
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Overwrite and delete the state file and its migration backup, then remove its lock file, for `wipe`.
+    ///
+    /// Takes the lock first so a running instance can't write the state right back, and asks for confirmation
+    /// unless `--yes` was given. Returns the paths that were wiped.
+    pub fn wipe_state_file(&mut self) -> Result<Vec<String>, Error> {
+        let state_file_path = self.state_file_path.clone().ok_or(Error::StateFileNotFound)?;
+        if !Path::new(state_file_path.as_str()).is_file() {
+            return Err(Error::StateFileNotFound);
+        }
+
+        self.lock_state_file(&state_file_path)?;
+
+        if !self.assume_yes {
+            let confirm = prompt_user(&format!("This destroys {} and the account keys and contacts in it for good. Continue? [y/N]: ", state_file_path.as_str()), true)?;
+            if !confirm.eq_ignore_ascii_case("yes") && !confirm.eq_ignore_ascii_case("y") {
+                println!("Aborting program.");
+                std::process::exit(exit_code::ABORTED);
+            }
+        }
+
+        let mut wiped = Vec::new();
+
+        // the backup left by a schema upgrade holds the same keys
+        for path in [state_file_path.to_string(), format!("{}.bak", state_file_path.as_str())] {
+            if Path::new(&path).is_file() {
+                utils::wipe_file(&path)?;
+                wiped.push(path);
+            }
+        }
+
+        // release the lock before deleting its file, Windows can't delete a file that is still open
+        self.state_lock = None;
+        std::fs::remove_file(format!("{}.lock", state_file_path.as_str()))
+            .or_else(|e| if e.kind() == std::io::ErrorKind::NotFound { Ok(()) } else { Err(e) })
+            .map_err(|_| Error::FailedToDeleteFile)?;
+
+        Ok(wiped)
+    }
+
+    fn lock_state_file(&mut self, state_file_path: &str) -> Result<(), Error> {
+        if self.state_lock.is_some() {
+            return Ok(());
+        }
+
+        match utils::lock_state_file(state_file_path) {
+            Ok(lock) => {
+                self.state_lock = Some(lock);
+                Ok(())
+            }
+            Err(Error::StateFileLocked) if self.force => {
+                self.log_warning(&format!("{} is locked by another instance, continuing anyway because of --force.", state_file_path));
+                Ok(())
+            }
+            Err(Error::StateFileLocked) => {
+                eprintln!(
+                    "ERROR: Another Coldwire instance is using {}. Close it first, or pass --force if you are sure it is not running.",
+                    state_file_path
+                );
+                Err(Error::StateFileLocked)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Returns true if the state was migrated from an older schema or is in another format than
+    /// `--state-format`, and needs to be saved again.
+    fn prompt_and_decrypt_state_file(&mut self, state_file_path: &str) -> Result<bool, Error> {
+        let (versioned, state_file_password_hash, state_file_password_salt) = self.read_state_file(state_file_path)?;
+        let old_version = versioned.version;
+
+        let versioned = self.migrate_state(state_file_path, versioned)?;
+
+        self.parse_decrypted_state_content(&versioned)?;
+
+        let migrated = old_version < consts::STATE_FILE_VERSION;
+        if migrated {
+            // keep the untouched original around in case the upgrade goes wrong
+            let backup_path = format!("{}.bak", state_file_path);
+            std::fs::copy(state_file_path, &backup_path)
+                .map_err(|_| Error::FailedToBackupStateFile)?;
+
+            if !self.quiet {
+                println!("[*] Upgraded state file from version {} to {}, the old file was saved as {}", old_version, consts::STATE_FILE_VERSION, backup_path);
+            }
+        }
+
+
+        self.state_file_password_hash = Some(state_file_password_hash);
+        self.state_file_password_hash_salt = Some(state_file_password_salt);
+
+        // written in the other --state-format, convert it now instead of on the first change
+        Ok(migrated || versioned.format != self.state_format)
+    }
+
+    /// Read and decrypt the state file, prompting for its password unless `--state-pass-file` gave it.
+    ///
+    /// Returns the decrypted content with its schema version, and the key and salt it was encrypted with.
+    fn read_state_file(&mut self, state_file_path: &str) -> Result<(state::VersionedState, Zeroizing<Vec<u8>>, Zeroizing<Vec<u8>>), Error> {
+        let mut state_file_password_salt = Zeroizing::new(vec![0u8; consts::ARGON2ID_SALT_SIZE]);
+
+        let mut file = File::open(&state_file_path)
+            .map_err(|_| Error::FailedToOpenFile)?;
+
+        let file_len = file.metadata()
+            .map_err(|_| Error::FailedToGetFileMetadata)?
+            .len();
+
+        // If size is less than argon2id salt size, authentication tag, and nonce, it must be a
+        // corrupted file.
+        if file_len < (consts::ARGON2ID_SALT_SIZE as u64 + 16 + consts::XCHACHA20POLY1305_NONCE_SIZE as u64) {
+            return Err(Error::InvalidStateFile);
+        }
+        
+        file.seek(std::io::SeekFrom::Start(file_len - consts::ARGON2ID_SALT_SIZE as u64))
+            .map_err(|_| Error::FailedToSeekInFile)?;
+        
+        file.read_exact(&mut state_file_password_salt)
+            .map_err(|_| Error::FailedToReadFile)?;
+
+
+        let state_file_password = match self.state_file_password.take() {
+            Some(p) => p,
+            None => prompt_user("Enter password: ", false)?,
+        };
+
+        
+        // Ciphertext + authentication tag
+        let ct_and_tag_len = file_len - consts::XCHACHA20POLY1305_NONCE_SIZE as u64 - consts::ARGON2ID_SALT_SIZE as u64;
+
+        if ct_and_tag_len > usize::MAX as u64 {
+            return Err(Error::StateFileTooLargeToReadIntoMemory);
+        }
+
+        let mut ct_and_tag = Zeroizing::new(vec![0u8; ct_and_tag_len as usize]);
+
+        file.seek(std::io::SeekFrom::Start(0))
+            .map_err(|_| Error::FailedToSeekInFile)?;
+
+        file.read_exact(&mut ct_and_tag)
+            .map_err(|_| Error::FailedToReadFile)?;
+
+        // Ciphertext's nonce
+        let mut nonce = Zeroizing::new(vec![0u8; consts::XCHACHA20POLY1305_NONCE_SIZE]);
+
+        file.seek(std::io::SeekFrom::Start(ct_and_tag_len))
+            .map_err(|_| Error::FailedToSeekInFile)?;
+
+        file.read_exact(&mut nonce)
+            .map_err(|_| Error::FailedToReadFile)?;
+
+
+        let state_file_password_hash = libcold::crypto::hash_argon2id(state_file_password.as_bytes(), &state_file_password_salt)
+        .map_err(|_| Error::Argon2IdHashingError)?;
+
+        let state_file_password_hash = Zeroizing::new(state_file_password_hash[..32].to_vec());
+
+        let plaintext = crypto::decrypt_xchacha20poly1305(&state_file_password_hash, &nonce, &ct_and_tag)
+            .map_err(|e| match e {
+                Error::XChaCha20DecryptionFailed => Error::WrongStateFilePasswordOrTampered,
+                e => e,
+            })?;
+
+        let versioned = state::split_version(plaintext.as_slice())?;
+
+        self.log_info(consts::VERBOSITY_DETAIL, &format!("Decrypted state file {} (schema version {})", state_file_path, versioned.version));
+
+        Ok((versioned, state_file_password_hash, state_file_password_salt))
+    }
+
+    /// Upgrade decrypted state to the current schema in memory, explaining a state file from a newer client.
+    fn migrate_state(&self, state_file_path: &str, versioned: state::VersionedState) -> Result<state::VersionedState, Error> {
+        let old_version = versioned.version;
+
+        state::migrate_state(versioned).inspect_err(|e| {
+            if let Error::UnsupportedStateFileVersion = e {
+                eprintln!(
+                    "ERROR: {} was written by a newer Coldwire (state version {}, this build supports up to {}). Please update.",
+                    state_file_path, old_version, consts::STATE_FILE_VERSION
+                );
+            }
+        })
+    }
+
+    /// Decrypt the state file and summarize it for `inspect`, in the `--format` requested.
+    ///
+    /// Read-only: the file is neither locked nor migrated on disk, so this works next to a running instance.
+    pub fn inspect_state_file(&mut self) -> Result<String, Error> {
+        let state_file_path = self.state_file_path.clone().ok_or(Error::StateFileNotFound)?;
+        if !Path::new(state_file_path.as_str()).is_file() {
+            return Err(Error::StateFileNotFound);
+        }
+
+        let (versioned, _, _) = self.read_state_file(&state_file_path)?;
+        let version = versioned.version;
+
+        let versioned = self.migrate_state(&state_file_path, versioned)?;
+        let format = versioned.format;
+        self.parse_decrypted_state_content(&versioned)?;
+
+        let last_modified = std::fs::metadata(state_file_path.as_str())
+            .and_then(|m| m.modified())
+            .ok()
+            .map(utils::utc_timestamp);
+
+        let contacts = self.contact_list.iter().flatten().map(|contact| {
+            let id = contact.additional_data.as_ref()
+                .and_then(|ad| std::str::from_utf8(ad).ok())
+                .and_then(|ad| json::extract_json_value(ad, "id"))
+                .unwrap_or_else(|| String::from("(unknown)"));
+
+            state::ContactSummary { id, verified: contact.state == libcold::ContactState::Verified }
+        }).collect();
+
+        let summary = state::StateSummary {
+            path: state_file_path.to_string(),
+            version,
+            current_version: consts::STATE_FILE_VERSION,
+            format,
+            last_modified,
+            server_url: self.server_url.as_ref().map(|u| u.to_string()),
+            user_id: self.user_id.as_ref().map(|u| u.to_string()),
+            auth_key_fingerprint: self.auth_public_key.as_ref().map(|k| crypto::key_fingerprint(k)),
+            contacts,
+            queued_messages: self.queue.len(),
+            oldest_queued_at: self.queue.iter()
+                .map(|m| m.queued_at)
+                .min()
+                .map(|t| utils::utc_timestamp(UNIX_EPOCH + Duration::from_secs(t))),
+        };
+
+        match self.output_format {
+            OutputFormat::Human => Ok(summary.to_human()),
+            OutputFormat::Json => serde_json::to_string_pretty(&summary).map_err(|_| Error::FailedToSerializeConfig),
+        }
+    }
+
+    /// Write the contacts of the state file to the `export-contacts` file, signed with the account key so
+    /// `import-contacts` notices a changed file. Returns how many contacts were exported.
+    ///
+    /// Only ids, nicknames and key fingerprints are written, never ratchet or session secrets. Read-only
+    /// like `inspect`, the state file is neither locked nor migrated on disk.
+    pub fn export_contacts(&mut self) -> Result<usize, Error> {
+        let export_path = self.export_contacts.clone().ok_or(Error::ImpossibleConditionButRustForcesUsToReturnError)?;
+        let state_file_path = self.state_file_path.clone().ok_or(Error::StateFileNotFound)?;
+        if !Path::new(state_file_path.as_str()).is_file() {
+            return Err(Error::StateFileNotFound);
+        }
+
+        let (versioned, _, _) = self.read_state_file(&state_file_path)?;
+        let versioned = self.migrate_state(&state_file_path, versioned)?;
+        self.parse_decrypted_state_content(&versioned)?;
+
+        // every saved state file has its account keys
+        let (Some(auth_pk), Some(auth_sk)) = (self.auth_public_key.as_ref(), self.auth_secret_key.as_ref()) else {
+            return Err(Error::StateFileCorrupted);
+        };
+
+        let entries: Vec<contacts::ContactEntry> = self.contact_list.iter().flatten().filter_map(contacts::entry).collect();
+        let exported = entries.len();
+
+        let text = contacts::export(entries, auth_pk, auth_sk)?;
+        utils::write_state_atomic(&export_path, text.as_bytes())?;
+
+        Ok(exported)
+    }
+
+    /// Merge the contacts of the `import-contacts` file into the state file.
+    ///
+    /// New contacts are added pending verification, with the fingerprint from the file stored next to them.
+    /// Contacts we already have are left alone and a warning names those the file lists under another key.
+    /// A file signed by another account is a warning too, an error with `--strict`.
+    pub fn import_contacts(&mut self) -> Result<contacts::Merge, Error> {
+        let import_path = self.import_contacts.clone().ok_or(Error::ImpossibleConditionButRustForcesUsToReturnError)?;
+        let signed = contacts::parse(&contacts::read(&import_path)?)?;
+
+        let state_file_path = self.state_file_path.clone().ok_or(Error::StateFileNotFound)?;
+        if !Path::new(state_file_path.as_str()).is_file() {
+            return Err(Error::StateFileNotFound);
+        }
+
+        self.lock_state_file(&state_file_path)?;
+        let migrated = self.prompt_and_decrypt_state_file(&state_file_path)?;
+        self.state_file_path = Some(state_file_path);
+
+        if self.auth_public_key.as_ref().map(|k| k.as_slice()) != Some(signed.signer_key.as_slice()) {
+            self.warn_or_fail(
+                &format!(
+                    "{} was signed by another account (auth key {}, exported {}). Only import it if that is the auth key `inspect` shows on the machine you exported from.",
+                    import_path, crypto::key_fingerprint(&signed.signer_key), signed.exported_at
+                ),
+                Error::ContactsFileFromAnotherAccount,
+            )?;
+        }
+
+        let existing: Vec<contacts::ContactEntry> = self.contact_list.iter().flatten().filter_map(contacts::entry).collect();
+        let merge = contacts::merge(&existing, signed.contacts);
+
+        for conflict in &merge.conflicts {
+            self.log_warning(&format!(
+                "{} lists contact {} with key {} but yours has {}, kept yours. Verify them again before trusting either.",
+                import_path, conflict.id, conflict.theirs, conflict.ours
+            ));
+        }
+
+        for entry in &merge.added {
+            let mut contact = libcold::Contact::new().map_err(|_| Error::FailedToImportContact)?;
+            contact.additional_data = Some(contacts::additional_data(entry));
+
+            match &mut self.contact_list {
+                Some(vec) => vec.push(contact),
+                None => self.contact_list = Some(vec![contact]),
+            }
+        }
+
+        if migrated || !merge.added.is_empty() {
+            self.save_state_file()?;
+        }
+
+        Ok(merge)
+    }
+
+
+    fn parse_decrypted_state_content(&mut self, versioned: &state::VersionedState) -> Result<(), Error> {
+        for (tag, decoded) in state::decode_records(versioned)? {
+
+            if tag == "server_url" {
+                let utf8_string = Zeroizing::new(String::from_utf8(decoded.to_vec())
+                    .map_err(|_| Error::FailedToConvertBytesToUtf8)?);
+
+                self.server_url = Some(clean_server_url(utf8_string.to_string(), true)
+                    .map_err(|_| Error::InvalidServerUrl)?);
+
+            } else if tag == "auth_secret_key" {
+                self.auth_secret_key = Some(decoded);
+
+            } else if tag == "auth_public_key" {
+                self.auth_public_key = Some(decoded);
+
+            } else if tag == "user_id" {
+                let s = Zeroizing::new(String::from_utf8(decoded.to_vec())
+                        .map_err(|_| Error::FailedToConvertBytesToUtf8)?);
+
+                self.user_id = Some(s);
+
+            } else if tag == "contact" {
+                let contact = libcold::Contact::import_plain(decoded.as_slice())
+                    .map_err(|_| Error::FailedToImportContact)?;
+
+                match &mut self.contact_list {
+                    Some(vec) => vec.push(contact),
+                    None => self.contact_list = Some(vec![contact]),
+                }
+
+
+            } else if tag == "queue_next_id" {
+                let next_id = std::str::from_utf8(&decoded)
+                    .ok()
+                    .and_then(|n| n.parse().ok())
+                    .ok_or(Error::StateFileCorrupted)?;
+
+                self.queue.restore_next_id(next_id);
+
+            } else if tag == "queued" {
+                self.queue.restore(state::PendingMessage::decode(&decoded)?);
+
+            } else {
+                return Err(Error::StateFileCorrupted);
+            }
+            
+        }
+
+        metrics::record_queue_depth(self.queue.len());
+
+        Ok(())
+    }
+
+    fn save_state_file(&mut self) -> Result<(), Error> {
+        let state_file_path = self.state_file_path
+            .as_ref()
+            .unwrap();
+
+        let state_file_password_hash = self.state_file_password_hash
+            .as_ref()
+            .unwrap();
+
+        let state_file_password_hash_salt = self.state_file_password_hash_salt
+            .as_ref()
+            .unwrap();
+
+        if self.auth_secret_key.as_ref().is_none() || self.auth_public_key.as_ref().is_none() {
+            let (new_auth_pk, new_auth_sk) = libcold::crypto::generate_ml_dsa_87_keypair()
+                                                .map_err(|_| Error::FailedToGenerateAuthKeypair)?;
+
+            self.auth_public_key = Some(new_auth_pk);
+            self.auth_secret_key = Some(new_auth_sk);
+        }
+
+
+
+
+
+        let text = |tag: &str, value: &str| (tag.to_string(), Zeroizing::new(value.as_bytes().to_vec()));
+
+        let mut records: Vec<state::Record> = vec![
+            text("server_url", &self.server_url.as_ref().unwrap().to_string()),
+            (String::from("auth_public_key"), self.auth_public_key.clone().unwrap()),
+            (String::from("auth_secret_key"), self.auth_secret_key.clone().unwrap()),
+        ];
+
+        if let Some(user_id) = &self.user_id {
+            records.push(text("user_id", user_id.as_str()));
+        }
+
+        for contact in self.contact_list.iter().flatten() {
+            let contact_exported = contact.export_plain()
+                .map_err(|_| Error::FailedToExportContact)?;
+
+            records.push((String::from("contact"), Zeroizing::new(contact_exported.to_vec())));
+        }
+
+        records.push(text("queue_next_id", &self.queue.next_id().to_string()));
+
+        for message in self.queue.iter() {
+            records.push((String::from("queued"), message.encode()));
+        }
+
+
+        let payload_plaintext = state::encode_state(&records, self.state_format)?;
+
+        let (encrypted_payload, encrypted_payload_nonce) = crypto::encrypt_xchacha20poly1305(state_file_password_hash, payload_plaintext.as_slice(), None, 0)?;
+
+        let mut final_payload_plaintext = Zeroizing::new(Vec::with_capacity(
+                encrypted_payload.as_slice().len() +
+                consts::XCHACHA20POLY1305_NONCE_SIZE + 
+                consts::ARGON2ID_SALT_SIZE
+            )
+        );
+        final_payload_plaintext.extend_from_slice(encrypted_payload.as_slice());
+        final_payload_plaintext.extend_from_slice(encrypted_payload_nonce.as_slice());
+        final_payload_plaintext.extend_from_slice(state_file_password_hash_salt.as_slice());
+
+
+        utils::write_state_atomic(state_file_path, final_payload_plaintext.as_slice())?;
+
+        self.state_dirty = false;
+        self.last_save = Some(Instant::now());
+
+        Ok(())
+
+    }
+
+    /// Record a change of the in-memory state, written by `autosave` once the interval has passed.
+    fn mark_state_changed(&mut self) -> Result<(), Error> {
+        self.state_dirty = true;
+        self.autosave()?;
+        Ok(())
+    }
+
+    /// Write the state file if it changed and `--autosave-secs` have passed since the last write,
+    /// returns whether it was written.
+    ///
+    /// Only while we hold the state file lock, so a second instance never gets its file overwritten.
+    pub fn autosave(&mut self) -> Result<bool, Error> {
+        let due = self.last_save.is_none_or(|t| t.elapsed() >= self.autosave);
+        if !due {
+            return Ok(false);
+        }
+
+        self.save_pending_state()
+    }
+
+    /// Write the state file now if it has unsaved changes, returns whether it was written.
+    ///
+    /// Called before waiting for input, so changes never sit unsaved while we are idle.
+    pub fn save_pending_state(&mut self) -> Result<bool, Error> {
+        if !self.state_dirty || self.state_lock.is_none() || self.state_file_password_hash.is_none() {
+            return Ok(false);
+        }
+
+        self.save_state_file()?;
+        Ok(true)
+    }
+
+    pub fn update_server_url(&mut self) -> Result<(), Error> {
+        // Server URLs given on the command line or in the config file are tried first, in order.
+        let mut preset_server_urls = std::mem::take(&mut self.server_urls).into_iter();
+
+        let server_url = loop {
+            let server_url = match preset_server_urls.next() {
+                Some(u) => u,
+                None => match PresetServerUrl::parse(&prompt_user("Enter server URL: ", true)?, self.default_server_port) {
+                    Ok(u) => u,
+                    Err(e) => {
+                        println!("ERROR: {}\n", e);
+                        continue
+                    }
+                },
+            };
+
+            if self.onion_only && !server_url.url.is_onion() {
+                println!("ERROR: only .onion servers are allowed with --onion-only\n");
+                continue
+            }
+
+            if requests::get_request(server_url.url.to_string(), None, None, &self.request_options(self.proxy_for(&server_url.url).as_ref())).is_ok() {
+                break server_url.url;
+            }
+
+            // only a URL that named no scheme falls back to plaintext http://
+            match server_url.http_fallback {
+                Some(http_server_url)
+                    if !((self.require_tls || self.strict) && http_server_url.is_plaintext())
+                        && requests::get_request(http_server_url.to_string(), None, None, &self.request_options(self.proxy_for(&http_server_url).as_ref())).is_ok() =>
+                {
+                    break http_server_url;
+                }
+                _ => {
+                    println!("Failed to fetch server URL ({}). Check the URl and your proxy settings.", server_url.url);
+                    continue
+                }
+            }
+        };
+
+
+        self.server_url = Some(server_url);
+
+        Ok(())
+    }
+
+    /// True if `host` is connected to directly: it is in the bypass list, or a loopback host
+    /// without `--proxy-loopback` (a proxy like Tor can't reach our loopback anyway).
+    pub(crate) fn bypasses_proxy(&self, host: &str) -> bool {
+        (!self.proxy_loopback && utils::is_loopback_host(host)) || utils::no_proxy_matches(&self.no_proxy_hosts, host)
+    }
+
+    /// The proxy to use for `server_url`, `None` if its host bypasses the proxy.
+    fn proxy_for(&self, server_url: &ServerUrl) -> Option<requests::ProxyInfo> {
+        if self.bypasses_proxy(&server_url.host) {
+            return None;
+        }
+
+        requests::stream_proxy(self.proxy.as_ref(), self.circuit_isolation.as_ref(), &server_url.to_string())
+    }
+
+    /// The settings every request to the server goes out with, through `proxy`.
+    fn request_options<'a>(&'a self, proxy: Option<&'a requests::ProxyInfo>) -> requests::RequestOptions<'a> {
+        request_options(
+            proxy, self.ca_certs.as_ref(), self.doh.as_ref(), self.timeout, &self.user_agent,
+            self.bind_address, self.insecure_skip_verify, &self.pins, self.min_tls, self.max_response_bytes,
+        )
+    }
+
+    /// Plain SOCKS5 resolves the server hostname locally, leaking it to the system resolver.
+    fn warn_if_socks5_dns_leak(&self, server_url: &ServerUrl) -> Result<(), Error> {
+        if let Some(proxy) = &self.proxy {
+            if proxy.proxy_type == requests::ProxyType::Socks5 && !utils::is_loopback_host(&server_url.host) {
+                self.warn_or_fail(
+                    &format!("SOCKS5 resolves {} through your local DNS resolver, use SOCKS5H to let the proxy resolve it.", server_url.host),
+                    Error::Socks5DnsLeakNotAllowed,
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn authenticate(&mut self) -> Result<(), Error> {
+        // Every request from here on goes to this one server, so the bypass list only has to be checked once.
+        if self.proxy.is_some() && self.proxy_for(self.server_url.as_ref().expect("Server_URL empty")).is_none() {
+            self.log_info(consts::VERBOSITY_INFO, "Server bypasses the proxy, connecting directly.");
+            self.proxy = None;
+        }
+
+        let server_url = self.server_url.as_ref().expect("Server_URL empty");
+        let user_id = self.user_id.as_ref();
+
+        // the state file may still name a server from before --onion-only was set
+        if self.onion_only && !server_url.is_onion() {
+            return Err(Error::NonOnionServerNotAllowed);
+        }
+
+        if server_url.is_plaintext() {
+            if self.require_tls {
+                return Err(Error::PlaintextServerUrlNotAllowed);
+            }
+            self.warn_or_fail(
+                &format!("{} uses plaintext http://, anyone on the network path can read and tamper with your traffic.", server_url),
+                Error::PlaintextServerUrlNotAllowed,
+            )?;
+        }
+
+        if self.strict || self.verbosity >= consts::VERBOSITY_DEBUG {
+            self.warn_if_socks5_dns_leak(server_url)?;
+        }
+
+        if self.auth_secret_key.as_ref().is_none() || self.auth_public_key.as_ref().is_none() {
+            let (new_auth_pk, new_auth_sk) = libcold::crypto::generate_ml_dsa_87_keypair()
+                                                .map_err(|_| Error::FailedToGenerateAuthKeypair)?;
+
+
+            self.auth_public_key = Some(new_auth_pk);
+            self.auth_secret_key = Some(new_auth_sk);
+        }
+
+
+        let auth_pk = self.auth_public_key.as_ref().unwrap();
+        let auth_sk = self.auth_secret_key.as_ref().unwrap();
+
+
+        let mut result = Zeroizing::new(Vec::new());
+
+        if user_id.is_some() {
+            let metadata = &[
+                ("user_id".to_string(), user_id.unwrap().to_string()),
+            ];
+
+            let proxy = requests::stream_proxy(self.proxy.as_ref(), self.circuit_isolation.as_ref(), &server_url.to_string());
+            result = requests::post_request(format!("{}authenticate/init", server_url.to_string()), None, Some(metadata), None, &self.request_options(proxy.as_ref()))?;
+
+        } else {
+            let pk_encoded = BASE64_STANDARD.encode(auth_pk);
+
+            let metadata = &[
+                ("public_key".to_string(), pk_encoded.to_string()),
+            ];
+
+            let proxy = requests::stream_proxy(self.proxy.as_ref(), self.circuit_isolation.as_ref(), &server_url.to_string());
+            result = requests::post_request(format!("{}authenticate/init", server_url.to_string()), None, Some(metadata), None, &self.request_options(proxy.as_ref()))?;
+       
+        }
+
+
+        let json_string = String::from_utf8(result.to_vec())
+            .map_err(|_| {
+                println!("Server did not respond with a valid JSON UTF-8 string, are you sure this is a Coldwire messenger server?");
+                Error::InvalidServerResponse
+            })?;
+
+
+        let challenge_base64_encoded = json::extract_json_value(&json_string, "challenge");
+        if challenge_base64_encoded.is_none() {
+            println!("Server did not respond with a valid JSON UTF-8 string, are you sure this is a Coldwire messenger server?");
+            return Err(Error::MalformedServerResponse);
+        }
+
+        let challenge_decoded = BASE64_STANDARD.decode(challenge_base64_encoded.as_ref().unwrap())
+            .map_err(|_| {
+                println!("Server did not give us a valid base64 encoded challenge.");
+                Error::FailedToDecodeBase64
+            })?;
+
+
+        let sig = libcold::crypto::generate_ml_dsa_87_signature(auth_sk, challenge_decoded.as_slice())
+            .map_err(|_| Error::FailedToSignChallenge)?;
+
+        let sig_base64_encoded = BASE64_STANDARD.encode(sig);
+
+        let metadata = &[
+                ("signature".to_string(), sig_base64_encoded),
+                ("challenge".to_string(), challenge_base64_encoded.as_ref().unwrap().to_string()),
+            ];
+
+
+        
+        let proxy = requests::stream_proxy(self.proxy.as_ref(), self.circuit_isolation.as_ref(), &server_url.to_string());
+        result = requests::post_request(format!("{}authenticate/verify", server_url.to_string()), None, Some(metadata), None, &self.request_options(proxy.as_ref()))?;
+
+        
+        let json_string = String::from_utf8(result.to_vec())
+            .map_err(|_| {
+                println!("Server did not respond with a valid JSON UTF-8 string, are you sure this is a Coldwire messenger server?");
+                Error::InvalidServerResponse
+            })?;
+
+
+        let user_id = json::extract_json_value(&json_string, "user_id");
+        let token = json::extract_json_value(&json_string, "token");
+
+
+        if user_id.is_none() || token.is_none() {
+            println!("Server did not respond with a `user_id` nor a `token`, either your account is missing or the server is not a coldwire messenger server.");
+            return Err(Error::MalformedServerResponse);
+        }
+
+        self.user_id = Some(Zeroizing::new(user_id.unwrap()));
+        self.auth_token = Some(Zeroizing::new(token.unwrap()));
+
+        let server_url = self.server_url.as_ref().expect("Server_URL empty");
+        self.log_to_file("INFO", &format!("authenticated to {} as {}", server_url, self.user_id().unwrap_or("")));
+
+        self.save_state_file()?;
+
+        Ok(())        
+    }
+
+    pub fn delete_contact(&mut self) -> Result<(), Error> {
+        let general_id = prompt_user("Choose a contact: ", true)?;
+
+            
+        
+        if let Some(contacts) = self.contact_list.as_mut() {
+        
+            let mut to_remove: Option<usize> = None;
+
+            for (i, contact) in contacts.iter_mut().enumerate() {
+                let ad_bytes = contact.additional_data.as_ref().expect("Contact does not have additional assosicated data. Impossible condition");
+
+                let ad_str = std::str::from_utf8(ad_bytes)
+                    .expect("additional_data is not valid UTF-8");
+
+                
+                let id = json::extract_json_value(ad_str, "id");
+                let nickname = json::extract_json_value(ad_str, "nickname");
+
+                if !(
+                    nickname.as_ref().map(|n| *n == *general_id).unwrap_or(false)
+                    || id.as_ref().map(|z| &**z == *general_id).unwrap_or(false)
+                    || i.to_string() == *general_id
+                ) {
+                    continue;
+                }
+
+                    
+                to_remove = Some(i);
+
+            }
+
+            if let Some(i) = to_remove {
+                contacts.remove(i);
+                println!("[*] Successfully deleted contact\n");
+                self.save_state_file()?;
+                return Ok(());
+            }
+        }
+        
+
+        println!("[!] Contact not found!");
+        Ok(())
+    }
+
+    pub fn add_contact(&mut self) -> Result<(), Error> {
+        let mut contact = libcold::Contact::new().expect("Could not create new contact instance");
+        let (mut id, mut question, mut answer);
+
+        loop {
+            id = prompt_user("Enter contact identifier: ", true)?;
+
+            if !utils::validate_identifier(&id) {
+                println!("\n[*] Invalid identifier! Ensure the identifier is correct format (i.e. 1234567890123456, 1234567890123456@example.com, etc)");
+                continue
+            }
+
+            break;
+        }
+
+        if let Some(contacts) = self.contact_list.as_mut() {
+            for (i, contact) in contacts.iter_mut().enumerate() {
+                let ad_bytes = contact.additional_data.as_ref().expect("Contact does not have additional assosicated data. Impossible condition");
+
+                let ad_str = std::str::from_utf8(ad_bytes)
+                    .expect("additional_data is not valid UTF-8");
+
+                
+                let cid = json::extract_json_value(ad_str, "id").unwrap();
+
+                if cid == id.as_str() {
+                    println!("You already have the contact saved!");
+                    return Ok(());
+                }
+            }
+        }
+
+
+
+        loop {
+            question = prompt_user("Enter a question: ", true)?;
+
+            answer = prompt_user("Enter an answer: ", true)?;
+
+            break
+        }
+
+        let output = contact.init_smp(question, answer)
+            .map_err(|_| Error::FailedToInitializeSmp)?;
+
+        let output = match output {
+            libcold::ContactOutput::Wire(w) => w,
+            _ => panic!("Impossible condition.")
+        };
+
+
+        let server_url = self.server_url.as_ref().unwrap();
+        let auth_token = self.auth_token.as_ref().unwrap();
+
+
+        let metadata = &[
+            ("recipient".to_string(), id.to_string()),
+        ];
+
+        let headers = &[
+            ("authorization".to_string(), format!("Bearer {}", auth_token.to_string())),
+        ];
+
+
+        let blob = Zeroizing::new(output[0].to_vec());
+
+
+        let proxy = requests::stream_proxy(self.proxy.as_ref(), self.circuit_isolation.as_ref(), &format!("contact:{}", metadata[0].1));
+        let response = requests::post_request(format!("{}data/send", server_url.to_string()), Some(headers), Some(metadata), Some(blob), &self.request_options(proxy.as_ref()))?;
+
+        let json_string = String::from_utf8(response.to_vec())
+            .map_err(|_| Error::FailedToConvertBytesToUtf8)?;
+
+
+        let status = json::extract_json_value(&json_string, "status");
+        if status.is_none() {
+            println!("Server did not respond with a valid JSON UTF-8 string.");
+            return Err(Error::InvalidJsonInServerResponse);
+        } else if status.unwrap() != "success" {
+            println!("Server responded with a non-success status.");
+            return Err(Error::NonSuccessServerStatus);
+        }
+
+
+
+        let ad_string = format!("{{\"id\":\"{}\",\"nickname\":\"\"}}", id.to_string());
+        let ad_bytes = ad_string.into_bytes(); 
+        contact.additional_data = Some(Zeroizing::new(ad_bytes));
+
+        match &mut self.contact_list {
+                Some(vec) => vec.push(contact),
+                None => self.contact_list = Some(vec![contact]),
+            }
+
+        self.save_state_file()?;
+
+        Ok(())
+    }
+
+
+    pub fn print_contact_list(&mut self) {
+        if let Some(contacts) = self.contact_list.as_ref() {
+            for (i, contact) in contacts.iter().enumerate() {
+                let ad_bytes = contact.additional_data.as_ref().expect("Contact does not have additional assosicated data. Impossible condition");
+
+                let contact_state = if contact.state  == libcold::ContactState::Verified {
+                    "Verified"
+                } else {
+                    "Pending verification"
+                };
+
+                let ad_str = std::str::from_utf8(ad_bytes)
+                    .expect("additional_data is not valid UTF-8");
+
+                
+                let id = json::extract_json_value(ad_str, "id");
+                let nickname = json::extract_json_value(ad_str, "nickname");
+
+                if nickname.is_some() && !nickname.as_ref().unwrap().is_empty() {
+                    println!("[{}]: {} ({})", i, nickname.unwrap(), contact_state)
+                } else if id.is_some() {
+                    println!("[{}]: {} ({})", i, id.unwrap(), contact_state)
+                } else {
+                    println!("{}", ad_str);
+                    panic!("Congraulations, you have discovered a bug");
+                }
+            }
+        } else {
+            println!("[*] You currently have no contacts in your list.");
+        }
+    }
+    
+    pub fn send_message(&mut self) -> Result<(), Error> {
+        let server_url = self.server_url.as_ref().unwrap().clone();
+        let auth_token = self.auth_token.as_ref().unwrap();
+
+        let headers = &[
+            ("authorization".to_string(), format!("Bearer {}", auth_token.to_string())),
+        ];
+
+
+
+        let general_id = prompt_user("Choose a contact: ", true)?;
+
+        if let Some(contacts) = self.contact_list.as_mut() {
+            for (i, contact) in contacts.iter_mut().enumerate() {
+                let ad_bytes = contact.additional_data.as_ref().expect("Contact does not have additional assosicated data. Impossible condition");
+
+                let ad_str = std::str::from_utf8(ad_bytes)
+                    .expect("additional_data is not valid UTF-8");
+
+                
+                let id = json::extract_json_value(ad_str, "id");
+                let nickname = json::extract_json_value(ad_str, "nickname");
+
+                if !(
+                    nickname.as_ref().map(|n| *n == *general_id).unwrap_or(false)
+                    || id.as_ref().map(|z| &**z == *general_id).unwrap_or(false) || i.to_string() == *general_id
+                ) {
+                    continue;
+                }
+
+                if contact.state != libcold::ContactState::Verified {
+                    println!("[!] Contact is not verified!");
+                    println!("[!] Please wait until they're verified and try again.");
+                    return Ok(());
+                }
+
+        
+                let message = prompt_user("Enter your message: ", true)?;
+
+                if message.is_empty() {
+                    println!("[!] Message cannot be empty");
+                    return Ok(());
+                }
+
+
+                let output = contact.send_message(&message)
+                    .map_err(|_| {
+                        Error::FailedToPrepareMessage
+                    })?;
+
+
+                let mut queued = false;
+
+                if let libcold::ContactOutput::Wire(output) = output {
+                    let metadata = &[
+                        ("recipient".to_string(), id.unwrap().to_string()),
+                    ];
+
+
+                    for blob in output {
+                        let blob = Zeroizing::new(blob.to_vec());
+
+                        // the ciphertext can't be produced again, keep it for later rather than losing the message,
+                        // and once one part is queued every later one has to wait behind it to keep the order
+                        if !self.queue.is_empty() {
+                            self.queue.push(&metadata[0].1, blob, unix_now(), self.queue_max)?;
+                            queued = true;
+                            continue;
+                        }
+
+                        let proxy = requests::stream_proxy(self.proxy.as_ref(), self.circuit_isolation.as_ref(), &format!("contact:{}", metadata[0].1));
+                        // not `self.request_options`, the loop holds `self.contact_list` mutably
+                        let options = request_options(
+                            proxy.as_ref(), self.ca_certs.as_ref(), self.doh.as_ref(), self.timeout, &self.user_agent,
+                            self.bind_address, self.insecure_skip_verify, &self.pins, self.min_tls, self.max_response_bytes,
+                        );
+                        // a send is never retried, it could be delivered twice (see `requests::Idempotency`)
+                        let response = match requests::post_request(format!("{}data/send", server_url.to_string()), Some(headers), Some(metadata), Some(blob.clone()), &options) {
+                            Err(e) if e.is_transient() && self.queue_max > 0 => {
+                                self.queue.push(&metadata[0].1, blob, unix_now(), self.queue_max)?;
+                                queued = true;
+                                continue;
+                            }
+                            result => result?,
+                        };
+
+                        check_send_status(&response)?;
+                    }
+                }   
+
+
+                contact.i_confirm_message_has_been_sent()
+                    .map_err(|_| {
+                        Error::FailedToUnlockMessage
+                    })?;
+
+                self.save_state_file()?;
+
+                if queued {
+                    metrics::record_queue_depth(self.queue.len());
+                    println!("[!] The relay is unreachable, the message was queued ({} waiting) and will be sent once it is back.", self.queue.len());
+                }
+
+                return Ok(());
+        
+            }
+        }
+
+        println!("\n[!] Did not find the specificed contact.");
+
+        Ok(())
+
+    }
+    
+    /// Number of messages waiting in the outbound queue.
+    pub fn queued_messages(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Send the queued messages oldest first, saving the state file after each one the relay accepted so a
+    /// crash doesn't send it again.
+    ///
+    /// Only the recipient goes with each message, the relay can't tell a message it already got from a
+    /// new one: if the send that queued it had reached the relay before failing, it is delivered twice.
+    ///
+    /// Returns how many were sent. A transient failure (`Error::is_transient`) stops the flush, what is
+    /// left stays queued for the next attempt.
+    pub fn flush_queue(&mut self) -> Result<usize, Error> {
+        let server_url = self.server_url.as_ref().expect("Server_URL empty").clone();
+        let auth_token = self.auth_token.as_ref().expect("auth_token empty");
+
+        let headers = &[
+            ("authorization".to_string(), format!("Bearer {}", auth_token.as_str())),
+        ];
+
+        let mut sent = 0;
+
+        while let Some(message) = self.queue.front() {
+            let message_id = message.id;
+            let metadata = &[
+                ("recipient".to_string(), message.recipient.clone()),
+            ];
+            let blob = message.blob.clone();
+
+            let proxy = requests::stream_proxy(self.proxy.as_ref(), self.circuit_isolation.as_ref(), &format!("contact:{}", metadata[0].1));
+            let response = requests::post_request(format!("{}data/send", server_url), Some(headers), Some(metadata), Some(blob), &self.request_options(proxy.as_ref()))?;
+            check_send_status(&response)?;
+
+            self.queue.remove(message_id);
+            metrics::record_queue_depth(self.queue.len());
+            self.save_state_file()?;
+            sent += 1;
+        }
+
+        Ok(sent)
+    }
+
+    pub fn check_for_new_data(&mut self, old_acks: Vec<String>) -> Result<Vec<String>, Error> {
+        let server_url = self.server_url.as_ref().unwrap().clone();
+        let auth_token = self.auth_token.as_ref().unwrap();
+
+
+        let headers = &[
+            ("authorization".to_string(), format!("Bearer {}", auth_token.to_string())),
+        ];
+
+
+        let mut acks: Vec<String> = Vec::new();
+
+        let metadata_list: Option<(String, Vec<String>)> = Some(
+                ("acks".to_string(), old_acks.clone()),
+            );
+
+
+        // polling is idempotent, the acks only confirm what we already processed
+        let response = self.with_reconnect(requests::Idempotency::Idempotent, |cfg| {
+            let proxy = requests::stream_proxy(cfg.proxy.as_ref(), cfg.circuit_isolation.as_ref(), &server_url.to_string());
+            let metadata = if old_acks.is_empty() { None } else { metadata_list.as_ref() };
+
+            match requests::get_request(format!("{}data/longpoll", server_url.to_string()), Some(headers), metadata, &cfg.request_options(proxy.as_ref())) {
+                // the relay holds the poll open until there is data, running out of time only means there was none
+                Err(Error::RequestTimedOut) => Ok(None),
+                result => result.map(Some),
+            }
+        });
+
+        let response = match response {
+            Ok(Some(response)) => response,
+            Ok(None) => {
+                println!("Data longpoll request timed out, you don't have any new unreceived data.");
+                return Ok(acks);
+            }
+            Err(e) if e.is_transient() => {
+                println!("Could not reach the server, no new data was fetched.");
+                return Ok(acks);
+            }
+            Err(e) => return Err(e),
+        };
+
+        metrics::record_sync(unix_now());
+
+        // the acked blobs were processed, make sure their state is on disk
+        if !old_acks.is_empty() {
+            self.save_pending_state()?;
+        }
+
+        
+        let new_data = utils::decode_blob_stream(&response)?;
+        let new_data = utils::parse_blobs(new_data)?;
+
+
+
+
+        for data in &new_data {
+            let mut cl = self.contact_list.as_mut();
+
+            let mut to_remove: Option<usize> = None;
+
+            // If we do not have the contact instance, we create it.
+            if cl.is_none() {
+                let mut contact = libcold::Contact::new().expect("Could not create new contact instance");
+                
+                let ad_string = format!("{{\"id\":\"{}\",\"nickname\":\"\"}}", data.sender.to_string());
+                let ad_bytes = ad_string.into_bytes(); 
+                contact.additional_data = Some(Zeroizing::new(ad_bytes));
+
+
+                match &mut self.contact_list {
+                    Some(vec) => vec.push(contact),
+                    None => self.contact_list = Some(vec![contact]),
+                    
+                }
+
+                cl = self.contact_list.as_mut();
+            }
+
+
+            let cl = cl.unwrap();
+
+            // println!("{:?} Sent by {:?}, ack id is: {:?}", data.blob, data.sender, data.ack_id);
+            for (i, contact) in cl.iter_mut().enumerate() {
+                let ad_bytes = contact.additional_data.as_ref().expect("Contact does not have additional assosicated data. Impossible condition");    
+                let ack_id = general_purpose::URL_SAFE_NO_PAD.encode(data.ack_id);
+
+                let ad_str = std::str::from_utf8(ad_bytes)
+                    .expect("additional_data is not valid UTF-8");
+
+                    
+                let id = json::extract_json_value(ad_str, "id").unwrap();
+                let nickname = json::extract_json_value(ad_str, "nickname").unwrap();
+                if id != data.sender {
+                    continue
+                }
+
+
+                // TODO: Dont panic.
+                let output = contact.process(data.blob.as_slice());
+                if output.is_err() {
+                    println!("Failed to process blob from contact ({}), we are removing them from our list.", id);
+                    to_remove = Some(i);
+                    break;
+                }
+
+                let output = output.unwrap();
+
+                if contact.state  == libcold::ContactState::Uninitialized {
+                    if nickname.is_empty() {
+                        println!("Contact ({}) have failed SMP verification!", id);
+                    } else {
+                        println!("Contact ({}) nicknamed ({}) have failed SMP verification!", id, nickname);
+                    }
+                    
+                    to_remove = Some(i);
+                }
+
+                let metadata = &[
+                        ("recipient".to_string(), id.to_string()),
+                    ];
+                
+                if let libcold::ContactOutput::None = output {
+                    acks.push(ack_id.clone());
+                    continue;
+                
+                } else if let libcold::ContactOutput::Wire(output) = output {
+                    for blob in output {
+                        let blob = Zeroizing::new(blob.to_vec());
+
+                        let proxy = requests::stream_proxy(self.proxy.as_ref(), self.circuit_isolation.as_ref(), &format!("contact:{}", metadata[0].1));
+                        // not `self.request_options`, the loop holds `self.contact_list` mutably
+                        let options = request_options(
+                            proxy.as_ref(), self.ca_certs.as_ref(), self.doh.as_ref(), self.timeout, &self.user_agent,
+                            self.bind_address, self.insecure_skip_verify, &self.pins, self.min_tls, self.max_response_bytes,
+                        );
+                        let response = requests::post_request(format!("{}data/send", server_url.to_string()), Some(headers), Some(metadata), Some(blob), &options)?;
+
+                        let json_string = String::from_utf8(response.to_vec())
+                            .map_err(|_| Error::FailedToConvertBytesToUtf8)?;
+
+
+                        let status = json::extract_json_value(&json_string, "status");
+                        if status.is_none() {
+                            println!("Server did not respond with a valid JSON UTF-8 string.");
+                            return Err(Error::InvalidJsonInServerResponse);
+                        } else if status.unwrap() != "success" {
+                            println!("Server responded with a non-success status.");
+                            return Err(Error::NonSuccessServerStatus);
+                        }
+                    }
+
+                } else if let libcold::ContactOutput::Prompt(output) = output {
+                    let question = output.question;
+
+                    println!("Contact ({}) wants to verify you, Please answer the question below.", id);
+
+                    println!("Question: {}", question);
+
+                
+                    let answer_str = prompt_user("Answer: ", false)?;
+
+                    let answer = libcold::UserAnswer::new(answer_str).expect("Failed to create answer");
+
+                    let output = match contact.provide_smp_answer(answer) {
+                        Ok(output) => output,
+                        Err(e) => {
+                            println!("We errored while processing SMP answer: {:?}", e);
+                            continue;
+                        }
+                    };
+
+                    let blobs = if let libcold::ContactOutput::Wire(blobs) = output {
+                        blobs
+                    } else {
+                        panic!("Expected Wire output, got something else!");
+                    };
+
+
+                    for blob in blobs {
+                        let blob = Zeroizing::new(blob.to_vec());
+
+                        let proxy = requests::stream_proxy(self.proxy.as_ref(), self.circuit_isolation.as_ref(), &format!("contact:{}", metadata[0].1));
+                        // not `self.request_options`, the loop holds `self.contact_list` mutably
+                        let options = request_options(
+                            proxy.as_ref(), self.ca_certs.as_ref(), self.doh.as_ref(), self.timeout, &self.user_agent,
+                            self.bind_address, self.insecure_skip_verify, &self.pins, self.min_tls, self.max_response_bytes,
+                        );
+                        let response = requests::post_request(format!("{}data/send", server_url.to_string()), Some(headers), Some(metadata), Some(blob), &options)?;
+
+                        let json_string = String::from_utf8(response.to_vec())
+                            .map_err(|_| Error::FailedToConvertBytesToUtf8)?;
+
+
+                        let status = json::extract_json_value(&json_string, "status");
+                        if status.is_none() {
+                            println!("Server did not respond with a valid JSON UTF-8 string.");
+                            return Err(Error::InvalidJsonInServerResponse);
+                        } else if status.unwrap() != "success" {
+                            println!("Server responded with a non-success status.");
+                            return Err(Error::NonSuccessServerStatus);
+                        }
+
+                    }
+
+                } else if let libcold::ContactOutput::Message(output) = output {
+                    let message = sanitize_message(output.message);
+                    println!("[*] Contact ({}) sent you a new message:\n{}\n\n", id, message);
+
+                } else {
+                    println!("WHAT THE ACTUAL FUCK?????????? {:?}", output);
+                }   
+
+                acks.push(ack_id.clone());
+                continue;
+                
+
+
+
+            }
+            if let Some(i) = to_remove {
+                cl.remove(i);
+            }
+
+            self.mark_state_changed()?;
+        }
+
+
+    
+        Ok(acks)
+    }
+}
+
+
+/// "SOCKS5H 127.0.0.1:9050 (user alice) (password ***)", the password is never printed.
+fn describe_proxy(proxy: &requests::ProxyInfo) -> String {
+    let user_part = proxy.username
+        .as_ref()
+        .map(|u| format!(" (user {})", u.as_str()))
+        .unwrap_or_default();
+    let pass_part = if proxy.password.is_some() { " (password ***)" } else { "" };
+
+    format!("{} {}:{}{}{}", proxy.proxy_type, proxy.host, proxy.port, user_part, pass_part)
+}
+
+
+/// Sanitizes a string for terminal-safe printing.
+/// Removes ANSI escape sequences and replaces non-printable characters with '?'.
+pub fn sanitize_message(input: Zeroizing<String>) -> String {
+    let mut result = String::with_capacity(input.len());
+    let mut chars = input.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // Potential start of ANSI sequence
+            if let Some('[') = chars.peek() {
+                chars.next(); // consume '['
+                // Consume until a letter in the range @A-Za-z
+                while let Some(&next) = chars.peek() {
+                    if ('@'..='~').contains(&next) {
+                        chars.next(); // consume the final letter
+                        break;
+                    } else {
+                        chars.next(); // consume intermediate char
+                    }
+                }
+                // ANSI sequence skipped
+                continue;
+            } else {
+                // Lone ESC, replace with '?'
+                result.push('?');
+            }
+        } else if c.is_control() && !matches!(c, '\n' | '\r' | '\t') {
+            // Replace other control chars with '?'
+            result.push('?');
+        } else {
+            // Safe printable char
+            result.push(c);
+        }
+    }
+
+    result
+}
+
+
+pub fn prompt_user(msg: &str, trim: bool) -> Result<Zeroizing<String>, Error> {
+    print!("{msg}");
+    std::io::stdout().flush()
+        .map_err(|_| Error::FailedToFlush)?;
+
+    let mut input = Zeroizing::new(String::new());
+    let read = shutdown::while_idle(|| std::io::stdin().read_line(&mut input))
+        .map_err(|_| Error::FailedToReadLine)?;
+
+    // stdin was closed (e.g. it was used for --proxy-pass-stdin), do not spin on empty input.
+    if read == 0 {
+        return Err(Error::FailedToReadLine);
+    }
+
+    if trim {
+        return Ok(Zeroizing::new(input.trim().to_string()));
+    }
+
+    Ok(Zeroizing::new(input.to_string()))
+}
+
+
+/// Check the `{"status": "success"}` answer of a `data/send` request.
+fn check_send_status(response: &[u8]) -> Result<(), Error> {
+    let json_string = String::from_utf8(response.to_vec())
+        .map_err(|_| Error::FailedToConvertBytesToUtf8)?;
+
+    match json::extract_json_value(&json_string, "status") {
+        None => {
+            println!("Server did not respond with a valid JSON UTF-8 string.");
+            Err(Error::InvalidJsonInServerResponse)
+        }
+        Some(status) if status != "success" => {
+            println!("Server responded with a non-success status.");
+            Err(Error::NonSuccessServerStatus)
+        }
+        Some(_) => Ok(()),
+    }
+}
+
+
+/// The options of a request from the `Config` fields they come from, taken one by one so the send loops,
+/// which hold `self.contact_list` mutably, can borrow them next to it.
+#[allow(clippy::too_many_arguments)]
+fn request_options<'a>(
+    proxy: Option<&'a requests::ProxyInfo>,
+    root_certs: Option<&'a RootCerts>,
+    doh: Option<&'a DohResolver>,
+    timeout: Duration,
+    user_agent: &'a str,
+    bind_address: Option<IpAddr>,
+    insecure_skip_verify: bool,
+    pins: &'a [[u8; 32]],
+    min_tls: requests::TlsVersion,
+    max_response_bytes: u64,
+) -> requests::RequestOptions<'a> {
+    requests::RequestOptions {
+        proxy,
+        root_certs,
+        doh,
+        timeout,
+        user_agent,
+        bind_address,
+        insecure_skip_verify,
+        pins,
+        min_tls,
+        max_response_bytes,
+    }
+}
+
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+
+/// Like `prompt_user`, but the answer is not echoed when stdin is a terminal (Unix only).
+///
+/// Only the line ending is stripped, a password may start or end with spaces.
+pub(crate) fn prompt_secret(msg: &str) -> Result<Zeroizing<String>, Error> {
+    let input = shutdown::without_echo(|| prompt_user(msg, false))?;
+
+    Ok(Zeroizing::new(input.trim_end_matches(['\r', '\n']).to_string()))
+}
@@ -0,0 +1,258 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::cli::{FLAGS, Flag, FlagValue, SUBCOMMANDS};
+use crate::error::ConfigError;
+
+
+/// Shells `coldwire-desktop completions` can write a script for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+const SHELLS: &[&str] = &["bash", "zsh", "fish"];
+
+impl FromStr for Shell {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "bash" => Ok(Shell::Bash),
+            "zsh" => Ok(Shell::Zsh),
+            "fish" => Ok(Shell::Fish),
+            _ => Err(ConfigError::InvalidShell(s.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for Shell {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Shell::Bash => "bash",
+            Shell::Zsh => "zsh",
+            Shell::Fish => "fish",
+        })
+    }
+}
+
+
+/// The completion script for `shell`, generated from the flag table `parse_args_from` uses.
+pub fn completion_script(shell: Shell) -> String {
+    match shell {
+        Shell::Bash => bash_script(),
+        Shell::Zsh => zsh_script(),
+        Shell::Fish => fish_script(),
+    }
+}
+
+
+fn spellings(flag: &Flag) -> impl Iterator<Item = &'static str> {
+    std::iter::once(flag.name).chain(flag.aliases.iter().copied())
+}
+
+
+fn bash_script() -> String {
+    let words_for = |value: FlagValue| -> String {
+        FLAGS.iter()
+            .filter(|f| f.value == value)
+            .flat_map(spellings)
+            .collect::<Vec<_>>()
+            .join("|")
+    };
+
+    let mut out = String::from("# bash completion for coldwire-desktop, load with: source <(coldwire-desktop completions bash)\n");
+    out.push_str("_coldwire_desktop() {\n");
+    out.push_str("    local cur=\"${COMP_WORDS[COMP_CWORD]}\" prev=\"${COMP_WORDS[COMP_CWORD-1]}\"\n\n");
+    out.push_str("    case \"$prev\" in\n");
+    out.push_str(&format!("        {})\n            COMPREPLY=($(compgen -f -- \"$cur\"))\n            return ;;\n", words_for(FlagValue::Path)));
+    for f in FLAGS {
+        if let FlagValue::Choice(choices) = f.value {
+            out.push_str(&format!(
+                "        {})\n            COMPREPLY=($(compgen -W \"{}\" -- \"$cur\"))\n            return ;;\n",
+                spellings(f).collect::<Vec<_>>().join("|"),
+                choices.join(" "),
+            ));
+        }
+    }
+    out.push_str(&format!("        {})\n            return ;;\n", words_for(FlagValue::Text)));
+    out.push_str("        export-contacts|import-contacts)\n            [[ $COMP_CWORD -eq 2 ]] && COMPREPLY=($(compgen -f -- \"$cur\"))\n            return ;;\n");
+    out.push_str(&format!(
+        "        completions)\n            [[ $COMP_CWORD -eq 2 ]] && COMPREPLY=($(compgen -W \"{}\" -- \"$cur\"))\n            return ;;\n",
+        SHELLS.join(" "),
+    ));
+    out.push_str("    esac\n\n");
+
+    let flags: Vec<&str> = FLAGS.iter().flat_map(spellings).collect();
+    let subcommands: Vec<&str> = SUBCOMMANDS.iter().map(|(name, _)| *name).collect();
+    out.push_str(&format!("    local words=\"{}\"\n", flags.join(" ")));
+    out.push_str(&format!("    [[ $COMP_CWORD -eq 1 ]] && words=\"{} $words\"\n", subcommands.join(" ")));
+    out.push_str("    COMPREPLY=($(compgen -W \"$words\" -- \"$cur\"))\n");
+    out.push_str("}\n");
+    out.push_str("complete -o filenames -F _coldwire_desktop coldwire-desktop\n");
+
+    out
+}
+
+
+fn zsh_script() -> String {
+    let mut out = String::from("#compdef coldwire-desktop\n");
+    out.push_str("# zsh completion for coldwire-desktop, save as _coldwire-desktop in a directory on $fpath\n\n");
+    out.push_str("_arguments -s \\\n");
+
+    for f in FLAGS {
+        let names: Vec<&str> = spellings(f).collect();
+
+        // `(--state-file -f){--state-file,-f}` so either spelling hides the other once given
+        let (exclusion, spec) = if names.len() == 1 {
+            (String::new(), f.name.to_string())
+        } else {
+            (format!("({})", names.join(" ")), format!("{{{}}}", names.join(",")))
+        };
+        let exclusion = if f.repeatable { String::from("*") } else { exclusion };
+
+        let action = match f.value {
+            FlagValue::None => String::new(),
+            FlagValue::Path => String::from(":path:_files"),
+            FlagValue::Choice(choices) => format!(":value:({})", choices.join(" ")),
+            FlagValue::Text => String::from(":value: "),
+        };
+
+        if exclusion.is_empty() {
+            out.push_str(&format!("  '{}[{}]{}' \\\n", spec, zsh_escape(f.help), action));
+        } else {
+            out.push_str(&format!("  '{}'{}'[{}]{}' \\\n", exclusion, spec, zsh_escape(f.help), action));
+        }
+    }
+
+    let subcommands: Vec<String> = SUBCOMMANDS.iter()
+        .map(|(name, help)| format!("{}\\:\"{}\"", name, zsh_escape(help)))
+        .collect();
+    out.push_str(&format!("  '1::command:(({}))' \\\n", subcommands.join(" ")));
+    out.push_str(&format!("  '2::shell:({})'\n", SHELLS.join(" ")));
+
+    out
+}
+
+
+/// Escape `[`, `]` and `:` for an `_arguments` description, and `'` for the surrounding quotes.
+fn zsh_escape(s: &str) -> String {
+    s.replace('\'', "'\\''")
+        .replace('[', "\\[")
+        .replace(']', "\\]")
+        .replace(':', "\\:")
+}
+
+
+fn fish_script() -> String {
+    let mut out = String::from("# fish completion for coldwire-desktop, load with: coldwire-desktop completions fish | source\n");
+    out.push_str("complete -c coldwire-desktop -f\n");
+
+    for (name, help) in SUBCOMMANDS {
+        out.push_str(&format!("complete -c coldwire-desktop -n __fish_use_subcommand -a {} -d {}\n", name, fish_quote(help)));
+    }
+    out.push_str(&format!(
+        "complete -c coldwire-desktop -n '__fish_seen_subcommand_from completions' -a {}\n",
+        fish_quote(&SHELLS.join(" ")),
+    ));
+    out.push_str("complete -c coldwire-desktop -n '__fish_seen_subcommand_from export-contacts import-contacts' -F\n");
+
+    for f in FLAGS {
+        for name in spellings(f) {
+            let mut line = String::from("complete -c coldwire-desktop");
+            match name.strip_prefix("--") {
+                Some(long) => line.push_str(&format!(" -l {}", long)),
+                None => line.push_str(&format!(" -s {}", &name[1..])),
+            }
+
+            match f.value {
+                FlagValue::None => {}
+                FlagValue::Path => line.push_str(" -r -F"),
+                FlagValue::Choice(choices) => line.push_str(&format!(" -x -a {}", fish_quote(&choices.join(" ")))),
+                FlagValue::Text => line.push_str(" -x"),
+            }
+
+            line.push_str(&format!(" -d {}\n", fish_quote(f.help)));
+            out.push_str(&line);
+        }
+    }
+
+    out
+}
+
+
+fn fish_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::{find_flag, usage};
+
+    #[test]
+    fn test_shell_from_str() {
+        assert_eq!("bash".parse::<Shell>().unwrap(), Shell::Bash);
+        assert_eq!("ZSH".parse::<Shell>().unwrap(), Shell::Zsh);
+        assert_eq!("fish".parse::<Shell>().unwrap(), Shell::Fish);
+        assert_eq!("tcsh".parse::<Shell>().unwrap_err().to_string(), "Invalid shell: tcsh (allowed: bash, zsh, fish)");
+    }
+
+    #[test]
+    fn test_flag_table_matches_usage() {
+        // every flag in the option column of the usage has a table entry, so it gets completed
+        let options = usage().lines()
+            .map(str::trim_start)
+            .filter(|line| line.starts_with('-'))
+            .map(|line| line.split("  ").next().unwrap_or(line));
+
+        for option in options {
+            for word in option.split(|c: char| !(c.is_ascii_alphanumeric() || c == '-')) {
+                if word.len() > 2 && word.starts_with("--") {
+                    assert!(find_flag(word).is_some(), "{} is missing from FLAGS", word);
+                }
+            }
+        }
+
+        // and every table entry is documented
+        for f in FLAGS {
+            assert!(usage().contains(f.name), "{} is missing from the usage", f.name);
+        }
+    }
+
+    #[test]
+    fn test_bash_script() {
+        let script = completion_script(Shell::Bash);
+
+        assert!(script.contains("--state-file|-f|"));
+        assert!(script.contains("--config|"));
+        assert!(script.contains("compgen -W \"HTTP SOCKS4 SOCKS5 SOCKS5H\""));
+        assert!(script.contains("words=\"check bench-proxy inspect wipe export-contacts import-contacts setup completions $words\""));
+        assert!(script.ends_with("complete -o filenames -F _coldwire_desktop coldwire-desktop\n"));
+    }
+
+    #[test]
+    fn test_zsh_script() {
+        let script = completion_script(Shell::Zsh);
+
+        assert!(script.starts_with("#compdef coldwire-desktop\n"));
+        assert!(script.contains("'(--state-file -f)'{--state-file,-f}'[Path of the state file]:path:_files' \\\n"));
+        assert!(script.contains("'--proxy-type[Proxy protocol]:value:(HTTP SOCKS4 SOCKS5 SOCKS5H)' \\\n"));
+        assert!(script.contains("'*'{--server,-s}'[Server URL, repeat for fallback servers]:value: ' \\\n"));
+        assert!(script.contains("'--profile[Use a \\[profile.<name>\\] table of the config file]:value: ' \\\n"));
+    }
+
+    #[test]
+    fn test_fish_script() {
+        let script = completion_script(Shell::Fish);
+
+        assert!(script.contains("complete -c coldwire-desktop -l config -r -F -d 'Load options from a TOML config file'\n"));
+        assert!(script.contains("complete -c coldwire-desktop -s f -r -F -d 'Path of the state file'\n"));
+        assert!(script.contains("complete -c coldwire-desktop -l proxy-type -x -a 'HTTP SOCKS4 SOCKS5 SOCKS5H' -d 'Proxy protocol'\n"));
+        assert!(script.contains("complete -c coldwire-desktop -l debug -d 'Maximum verbosity'\n"));
+        assert!(script.contains("-n __fish_use_subcommand -a setup"));
+    }
+}
@@ -0,0 +1,517 @@
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::str::FromStr;
+
+use serde::Deserialize;
+use serde::de::{self, Deserializer, MapAccess, Visitor};
+use zeroize::Zeroizing;
+
+use crate::error::ConfigError;
+
+
+/// Refuse to load config files larger than this, nobody writes a 64 KiB config by hand.
+const MAX_CONFIG_FILE_SIZE: u64 = 64 * 1024;
+
+/// Profile names end up in table headers and error messages, keep them short.
+const MAX_PROFILE_NAME_LEN: usize = 64;
+
+
+/// Values loaded from a `--config` file.
+///
+/// Every field is optional so command-line flags can fill in or override whatever the file leaves out.
+#[derive(Debug, Default)]
+pub struct PartialConfig {
+    pub server_url: Option<Zeroizing<String>>,
+    pub default_server_port: Option<u64>,
+    pub state_file_path: Option<Zeroizing<String>>,
+    pub log_file: Option<Zeroizing<String>>,
+    pub profile_dir: Option<Zeroizing<String>>,
+
+    pub use_proxy: Option<bool>,
+    pub proxy_type: Option<String>,
+    pub proxy_addr: Option<Zeroizing<String>>,
+    pub proxy_user: Option<Zeroizing<String>>,
+    pub proxy_pass: Option<Zeroizing<String>>,
+
+    pub debug: Option<bool>,
+    pub require_tls: Option<bool>,
+    pub onion_only: Option<bool>,
+    pub strict_perms: Option<bool>,
+    pub strict: Option<bool>,
+    pub timeout: Option<u64>,
+}
+
+
+/// The name of a `[profile.<name>]` table, as given to `--profile`.
+///
+/// Only ASCII letters, digits, '-' and '_' are allowed so the name can be written as a bare TOML key.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProfileName(String);
+
+impl FromStr for ProfileName {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if !is_valid_profile_name(s) {
+            return Err(ConfigError::InvalidProfile(s.to_string()));
+        }
+
+        Ok(ProfileName(s.to_string()))
+    }
+}
+
+impl fmt::Display for ProfileName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+
+fn is_valid_profile_name(s: &str) -> bool {
+    !s.is_empty()
+        && s.len() <= MAX_PROFILE_NAME_LEN
+        && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+
+/// A parsed config file: the top-level options plus every `[profile.<name>]` table, in file order.
+#[derive(Debug, Default)]
+struct ConfigFile {
+    base: PartialConfig,
+    profiles: Vec<(String, PartialConfig)>,
+}
+
+impl ConfigFile {
+    /// Merge the fields of `profile` over the top-level options, or return the top-level
+    /// options alone when no profile is selected.
+    fn select(self, path: &str, profile: Option<&ProfileName>) -> Result<PartialConfig, ConfigError> {
+        let ConfigFile { base, mut profiles } = self;
+
+        let Some(name) = profile else {
+            return Ok(base);
+        };
+
+        let Some(index) = profiles.iter().position(|(n, _)| *n == name.0) else {
+            return Err(ConfigError::ProfileNotFound {
+                path: path.to_string(),
+                profile: name.to_string(),
+                available: profiles.into_iter().map(|(n, _)| n).collect(),
+            });
+        };
+
+        let (_, selected) = profiles.swap_remove(index);
+        Ok(selected.merge_over(base))
+    }
+}
+
+
+impl PartialConfig {
+    /// Fill every field left unset in `self` from `base`.
+    fn merge_over(self, base: PartialConfig) -> PartialConfig {
+        PartialConfig {
+            server_url: self.server_url.or(base.server_url),
+            default_server_port: self.default_server_port.or(base.default_server_port),
+            state_file_path: self.state_file_path.or(base.state_file_path),
+            log_file: self.log_file.or(base.log_file),
+            profile_dir: self.profile_dir.or(base.profile_dir),
+
+            use_proxy: self.use_proxy.or(base.use_proxy),
+            proxy_type: self.proxy_type.or(base.proxy_type),
+            proxy_addr: self.proxy_addr.or(base.proxy_addr),
+            proxy_user: self.proxy_user.or(base.proxy_user),
+            proxy_pass: self.proxy_pass.or(base.proxy_pass),
+
+            debug: self.debug.or(base.debug),
+            require_tls: self.require_tls.or(base.require_tls),
+            onion_only: self.onion_only.or(base.onion_only),
+            strict_perms: self.strict_perms.or(base.strict_perms),
+            strict: self.strict.or(base.strict),
+            timeout: self.timeout.or(base.timeout),
+        }
+    }
+}
+
+
+/// The keys of the top level and of a `[profile.<name>]` table, as written in the file.
+///
+/// Secrets go straight into `Zeroizing` strings, copies the TOML parser makes on the way are out of
+/// our reach.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileTable {
+    server_url: Option<Zeroizing<String>>,
+    default_server_port: Option<u64>,
+    state_file_path: Option<Zeroizing<String>>,
+    log_file: Option<Zeroizing<String>>,
+    profile_dir: Option<Zeroizing<String>>,
+
+    debug: Option<bool>,
+    require_tls: Option<bool>,
+    onion_only: Option<bool>,
+    strict_perms: Option<bool>,
+    strict: Option<bool>,
+    timeout: Option<u64>,
+
+    proxy: Option<ProxyTable>,
+
+    /// Only allowed at the top level, `profiles_in_order` rejects a nested one.
+    #[serde(default, deserialize_with = "profiles_in_order")]
+    profile: Vec<(String, FileTable)>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProxyTable {
+    enabled: Option<bool>,
+    #[serde(rename = "type")]
+    proxy_type: Option<String>,
+    addr: Option<Zeroizing<String>>,
+    user: Option<Zeroizing<String>>,
+    pass: Option<Zeroizing<String>>,
+}
+
+impl FileTable {
+    fn into_parts(self) -> (PartialConfig, Vec<(String, FileTable)>) {
+        // a [proxy] table implies --use-proxy unless it says `enabled = false`
+        let (use_proxy, proxy_type, proxy_addr, proxy_user, proxy_pass) = match self.proxy {
+            Some(p) => (Some(p.enabled.unwrap_or(true)), p.proxy_type, p.addr, p.user, p.pass),
+            None => (None, None, None, None, None),
+        };
+
+        let partial = PartialConfig {
+            server_url: self.server_url,
+            default_server_port: self.default_server_port,
+            state_file_path: self.state_file_path,
+            log_file: self.log_file,
+            profile_dir: self.profile_dir,
+
+            use_proxy,
+            proxy_type,
+            proxy_addr,
+            proxy_user,
+            proxy_pass,
+
+            debug: self.debug,
+            require_tls: self.require_tls,
+            onion_only: self.onion_only,
+            strict_perms: self.strict_perms,
+            strict: self.strict,
+            timeout: self.timeout,
+        };
+
+        (partial, self.profile)
+    }
+}
+
+
+/// The `[profile.<name>]` tables in file order, so a missing profile lists the others as written.
+fn profiles_in_order<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<(String, FileTable)>, D::Error> {
+    struct Profiles;
+
+    impl<'de> Visitor<'de> for Profiles {
+        type Value = Vec<(String, FileTable)>;
+
+        fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("a table of profiles")
+        }
+
+        fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+            let mut profiles = Vec::new();
+
+            while let Some((name, table)) = map.next_entry::<String, FileTable>()? {
+                if !is_valid_profile_name(&name) {
+                    return Err(de::Error::custom(format!("invalid profile name `{}` (use letters, digits, '-' and '_')", name)));
+                }
+                if !table.profile.is_empty() {
+                    return Err(de::Error::custom(format!("profile `{}` must not contain profiles", name)));
+                }
+
+                profiles.push((name, table));
+            }
+
+            Ok(profiles)
+        }
+    }
+
+    deserializer.deserialize_map(Profiles)
+}
+
+
+/// Load a TOML config file.
+///
+/// Unknown keys and tables are errors, a typo would otherwise silently leave the option at its
+/// default. Example:
+///
+/// ```toml
+/// server_url = "https://relay.example.org"
+/// default_server_port = 8443
+/// state_file_path = "/home/user/.coldwire/state"
+/// debug = false
+/// require_tls = true
+/// timeout = 120
+///
+/// [proxy]
+/// type = "SOCKS5"
+/// addr = "127.0.0.1:9050"
+/// ```
+///
+/// A `[proxy]` table implies `--use-proxy` unless `enabled = false` is set inside it.
+///
+/// Profiles hold the same keys as the top level and are only read when selected with `--profile`,
+/// their fields then override the top-level ones:
+///
+/// ```toml
+/// timeout = 60
+///
+/// [profile.work]
+/// server_url = "https://relay.work.example"
+/// profile_dir = "~/.coldwire/work"
+///
+/// [profile.work.proxy]
+/// addr = "127.0.0.1:9050"
+/// ```
+pub fn load_config_file(path: &str, profile: Option<&ProfileName>) -> Result<PartialConfig, ConfigError> {
+    let file = File::open(path)
+        .map_err(|e| ConfigError::ConfigFile(format!("Failed to open config file {}: {}", path, e)))?;
+
+    // Read one byte past the limit so we can tell an oversized file apart from one exactly at it, the
+    // file may grow after a metadata check (or be a FIFO without a length).
+    let mut content = Zeroizing::new(String::new());
+    file.take(MAX_CONFIG_FILE_SIZE + 1)
+        .read_to_string(&mut content)
+        .map_err(|e| ConfigError::ConfigFile(format!("Failed to read config file {}: {}", path, e)))?;
+
+    if content.len() as u64 > MAX_CONFIG_FILE_SIZE {
+        return Err(ConfigError::ConfigFile(format!("Config file {} is too large (max {} bytes)", path, MAX_CONFIG_FILE_SIZE)));
+    }
+
+    parse_config_file(path, &content)?.select(path, profile)
+}
+
+
+#[cfg(test)]
+pub(crate) fn parse_config(content: &str) -> Result<PartialConfig, ConfigError> {
+    parse_config_file("config.toml", content)?.select("config.toml", None)
+}
+
+
+fn parse_config_file(path: &str, content: &str) -> Result<ConfigFile, ConfigError> {
+    let table: FileTable = toml::from_str(content)
+        .map_err(|e| ConfigError::InvalidConfigFile { path: path.to_string(), reason: describe_error(content, &e) })?;
+
+    let (base, profiles) = table.into_parts();
+    let profiles = profiles
+        .into_iter()
+        .map(|(name, table)| (name, table.into_parts().0))
+        .collect();
+
+    Ok(ConfigFile { base, profiles })
+}
+
+
+/// "line <n>: <message>". The error's own `Display` quotes the offending line of the file, which may
+/// be the proxy password.
+fn describe_error(content: &str, e: &toml::de::Error) -> String {
+    let message = e.message().trim();
+
+    match e.span() {
+        Some(span) => {
+            let line = content.as_bytes()[..span.start.min(content.len())].iter().filter(|b| **b == b'\n').count() + 1;
+            format!("line {}: {}", line, message)
+        }
+        None => message.to_string(),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// What the file was rejected for, without the "Invalid config file <path>: " around it.
+    fn parse_error(content: &str) -> String {
+        match parse_config(content).unwrap_err() {
+            ConfigError::InvalidConfigFile { reason, .. } => reason,
+            e => panic!("unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn test_parse_config_full() {
+        let content = r#"
+# Coldwire config
+server_url = "https://relay.example.org"
+state_file_path = '/tmp/coldwire state'
+debug = true # trailing comment
+require_tls = true
+timeout = 120
+
+[proxy]
+type = "SOCKS5"
+addr = "127.0.0.1:9050"
+user = "alice"
+pass = "s3cr\"et"
+"#;
+
+        let partial = parse_config(content).unwrap();
+
+        assert_eq!(partial.server_url.as_deref().map(|s| s.as_str()), Some("https://relay.example.org"));
+        assert_eq!(partial.state_file_path.as_deref().map(|s| s.as_str()), Some("/tmp/coldwire state"));
+        assert_eq!(partial.debug, Some(true));
+        assert_eq!(partial.require_tls, Some(true));
+        assert_eq!(partial.timeout, Some(120));
+        assert_eq!(partial.use_proxy, Some(true));
+        assert_eq!(partial.proxy_type.as_deref(), Some("SOCKS5"));
+        assert_eq!(partial.proxy_addr.as_deref().map(|s| s.as_str()), Some("127.0.0.1:9050"));
+        assert_eq!(partial.proxy_user.as_deref().map(|s| s.as_str()), Some("alice"));
+        assert_eq!(partial.proxy_pass.as_deref().map(|s| s.as_str()), Some("s3cr\"et"));
+    }
+
+    #[test]
+    fn test_parse_config_onion_only() {
+        assert_eq!(parse_config("onion_only = true\n").unwrap().onion_only, Some(true));
+        assert!(parse_error("onion_only = \"yes\"\n").contains("expected a boolean"));
+    }
+
+    #[test]
+    fn test_parse_config_strict() {
+        assert_eq!(parse_config("strict = true\n").unwrap().strict, Some(true));
+        assert!(parse_error("strict = 1\n").contains("expected a boolean"));
+    }
+
+    #[test]
+    fn test_parse_config_default_server_port() {
+        assert_eq!(parse_config("default_server_port = 8443\n").unwrap().default_server_port, Some(8443));
+        assert!(parse_error("default_server_port = \"8443\"\n").contains("invalid type"));
+        assert!(parse_error("default_server_port = -1\n").contains("invalid value"));
+    }
+
+    #[test]
+    fn test_parse_config_profile_dir() {
+        let partial = parse_config("profile_dir = \"~/.coldwire/work\"\n").unwrap();
+        assert_eq!(partial.profile_dir.as_deref().map(|s| s.as_str()), Some("~/.coldwire/work"));
+        assert!(parse_error("profile_dir = 1\n").contains("expected a string"));
+    }
+
+    #[test]
+    fn test_parse_config_proxy_disabled() {
+        let partial = parse_config("[proxy]\nenabled = false\naddr = \"127.0.0.1:9150\"\n").unwrap();
+
+        assert_eq!(partial.use_proxy, Some(false));
+        assert_eq!(partial.proxy_addr.as_deref().map(|s| s.as_str()), Some("127.0.0.1:9150"));
+    }
+
+    #[test]
+    fn test_parse_config_errors() {
+        assert!(parse_error("unknown = \"x\"").contains("unknown field `unknown`"));
+        assert!(parse_error("debug = \"yes\"").contains("expected a boolean"));
+        assert!(parse_error("server_url = true").contains("expected a string"));
+        assert!(parse_error("timeout = \"60\"").contains("invalid type"));
+        assert!(parse_error("debug = 1").contains("expected a boolean"));
+        assert!(parse_error("server_url = \"abc").starts_with("line 1: "));
+        assert!(parse_error("[tor]").contains("unknown field `tor`"));
+        assert!(parse_error("timeout = 5\njust a line").starts_with("line 2: "));
+        assert!(parse_error("[proxy]\nport = 9050").contains("unknown field `port`"));
+        assert!(parse_error("[profile.work.tor]").contains("unknown field `tor`"));
+        assert!(parse_config("[profile.w rk]").is_err());
+        assert!(parse_error("[profile.\"w rk\"]").contains("invalid profile name"));
+        assert!(parse_error("[profile.work.profile.home]").contains("must not contain profiles"));
+        assert!(parse_error("[profile.work]\nbogus = 1").contains("unknown field `bogus`"));
+    }
+
+    #[test]
+    fn test_parse_config_error_hides_line() {
+        let err = parse_error("[proxy]\npass = \"s3cret");
+
+        assert!(err.starts_with("line 2: "), "{}", err);
+        assert!(!err.contains("s3cret"), "{}", err);
+    }
+
+    const PROFILES: &str = r#"
+server_url = "https://relay.example.org"
+timeout = 60
+
+[proxy]
+addr = "127.0.0.1:9050"
+
+[profile.work]
+server_url = "https://relay.work.example"
+state_file_path = "/tmp/work"
+
+[profile.work.proxy]
+enabled = false
+
+[profile.home]
+state_file_path = "/tmp/home"
+"#;
+
+    #[test]
+    fn test_profiles_ignored_unless_selected() {
+        let partial = parse_config(PROFILES).unwrap();
+
+        assert_eq!(partial.server_url.as_deref().map(|s| s.as_str()), Some("https://relay.example.org"));
+        assert!(partial.state_file_path.is_none());
+        assert_eq!(partial.use_proxy, Some(true));
+    }
+
+    #[test]
+    fn test_profile_merges_over_top_level() {
+        let work: ProfileName = "work".parse().unwrap();
+        let partial = parse_config_file("config.toml", PROFILES).unwrap().select("config.toml", Some(&work)).unwrap();
+
+        assert_eq!(partial.server_url.as_deref().map(|s| s.as_str()), Some("https://relay.work.example"));
+        assert_eq!(partial.state_file_path.as_deref().map(|s| s.as_str()), Some("/tmp/work"));
+        assert_eq!(partial.timeout, Some(60));
+        assert_eq!(partial.use_proxy, Some(false));
+        assert_eq!(partial.proxy_addr.as_deref().map(|s| s.as_str()), Some("127.0.0.1:9050"));
+
+        let home: ProfileName = "home".parse().unwrap();
+        let partial = parse_config_file("config.toml", PROFILES).unwrap().select("config.toml", Some(&home)).unwrap();
+
+        assert_eq!(partial.server_url.as_deref().map(|s| s.as_str()), Some("https://relay.example.org"));
+        assert_eq!(partial.state_file_path.as_deref().map(|s| s.as_str()), Some("/tmp/home"));
+        assert_eq!(partial.use_proxy, Some(true));
+    }
+
+    #[test]
+    fn test_profile_not_found_lists_available() {
+        let name: ProfileName = "personal".parse().unwrap();
+
+        let err = parse_config_file("config.toml", PROFILES).unwrap().select("config.toml", Some(&name)).unwrap_err();
+        assert_eq!(err.to_string(), "Invalid --profile for config file config.toml: profile `personal` not found (available: work, home)");
+        assert_eq!(err.argument(), Some("--profile"));
+        assert_eq!(err.input(), Some("personal"));
+
+        let err = parse_config_file("config.toml", "timeout = 5").unwrap().select("config.toml", Some(&name)).unwrap_err();
+        assert_eq!(err.to_string(), "Invalid --profile for config file config.toml: profile `personal` not found (available: none defined)");
+    }
+
+    #[test]
+    fn test_load_config_file_size_limit() {
+        let dir = std::env::temp_dir().join(format!("coldwire-test-config-size-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+
+        // a comment exactly at the limit is still read
+        let at_limit = format!("#{}", "x".repeat(MAX_CONFIG_FILE_SIZE as usize - 1));
+        std::fs::write(&path, &at_limit).unwrap();
+        assert!(load_config_file(path.to_str().unwrap(), None).is_ok());
+
+        std::fs::write(&path, format!("{}x", at_limit)).unwrap();
+        let err = load_config_file(path.to_str().unwrap(), None).unwrap_err();
+        assert!(matches!(&err, ConfigError::ConfigFile(reason) if reason.contains("too large")), "{}", err);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_profile_name_from_str() {
+        assert!("work".parse::<ProfileName>().is_ok());
+        assert!("my-relay_2".parse::<ProfileName>().is_ok());
+        assert!("".parse::<ProfileName>().is_err());
+        assert!("a.b".parse::<ProfileName>().is_err());
+        assert!("with space".parse::<ProfileName>().is_err());
+        assert!("x".repeat(65).parse::<ProfileName>().is_err());
+    }
+}
@@ -1,5 +1,84 @@
 pub const DEFAULT_PROXY_ADDR: &str = "127.0.0.1:9050";
 
+/// SOCKS ports of the Tor daemon and Tor Browser.
+pub const TOR_SOCKS_PORTS: &[u16] = &[9050, 9150];
+
+/// How long to wait for each Tor port when auto-detecting the proxy.
+pub const TOR_PROBE_TIMEOUT_MS: u64 = 500;
+
+/// Default for `--user-agent`, names the client without giving away the platform.
+pub const DEFAULT_USER_AGENT: &str = concat!("coldwire-desktop/", env!("CARGO_PKG_VERSION"));
+
+/// Default for `--timeout`, Tor circuits can take a while to build.
+pub const DEFAULT_TIMEOUT_SECS: u64 = 60;
+
+/// `-v`: which proxy is used and why (Tor auto-detection, NO_PROXY bypass).
+pub const VERBOSITY_INFO: u8 = 1;
+
+/// `-vv`: also state file details like its schema version.
+pub const VERBOSITY_DETAIL: u8 = 2;
+
+/// `-vvv` or `--debug`: everything, including extra proxy sanity checks.
+pub const VERBOSITY_DEBUG: u8 = 3;
+
+/// Default for `--reconnect-base-ms`, the backoff before the first reconnect attempt.
+pub const DEFAULT_RECONNECT_BASE_MS: u64 = 500;
+
+/// Default for `--reconnect-max-ms`, no single backoff grows beyond this.
+pub const DEFAULT_RECONNECT_MAX_MS: u64 = 60_000;
+
+/// Default for `--connect-retries`, how often the first connection at startup is retried before giving up.
+pub const DEFAULT_CONNECT_RETRIES: u32 = 3;
+
+/// Default for `--samples`, how many connections `bench-proxy` opens.
+pub const DEFAULT_BENCH_SAMPLES: u32 = 10;
+
+/// Most connections `bench-proxy` opens, so a typo doesn't keep a Tor circuit busy for hours.
+pub const MAX_BENCH_SAMPLES: u32 = 1000;
+
+/// `bench-proxy` fails when more than this fraction of its connections fail.
+pub const BENCH_MAX_FAILED_FRACTION: f64 = 0.2;
+
+/// Default for `--max-response-bytes`, far above any legitimate relay answer.
+pub const DEFAULT_MAX_RESPONSE_BYTES: u64 = 16 * 1024 * 1024;
+
+/// Default for `--autosave-secs`, the longest state changes wait in memory while messages are processed.
+pub const DEFAULT_AUTOSAVE_SECS: u64 = 30;
+
+/// File names inside `--profile-dir`, the lock is `<state file>.lock` like everywhere else.
+pub const PROFILE_STATE_FILE_NAME: &str = "state";
+pub const PROFILE_LOG_FILE_NAME: &str = "coldwire.log";
+
+/// Keyring service name `--proxy-pass-keyring` entries are looked up under, the entry name is the user.
+pub const KEYRING_SERVICE: &str = "coldwire-desktop";
+
+/// Length of a v3 onion address without the ".onion" suffix.
+pub const ONION_V3_ADDRESS_LEN: usize = 56;
+
+/// Default for `--queue-max`, how many messages may wait in the state file for the relay to come back.
+pub const DEFAULT_QUEUE_MAX: usize = 100;
+
+/// Maximum size of a `--proxy-pass-file` or `--state-pass-file`, guards against reading a huge file by mistake.
+pub const MAX_PASSWORD_FILE_SIZE: u64 = 4 * 1024;
+
+/// Maximum size of an `@path` response file.
+pub const MAX_RESPONSE_FILE_SIZE: u64 = 64 * 1024;
+
+/// Largest `--server-file` we read.
+pub const MAX_SERVER_FILE_SIZE: u64 = 64 * 1024;
+
+/// How deeply response files may include other response files.
+pub const MAX_RESPONSE_FILE_DEPTH: usize = 8;
+
+/// Schema version of the decrypted state file content, see `state::migrate_state`.
+pub const STATE_FILE_VERSION: u32 = 3;
+
+/// Version of the `export-contacts` file format, see `contacts::parse`.
+pub const CONTACTS_FILE_VERSION: u32 = 1;
+
+/// Largest contacts file `import-contacts` reads.
+pub const MAX_CONTACTS_FILE_SIZE: u64 = 4 * 1024 * 1024;
+
 /// Must be always 16 bytes for interoperability with implementations that use libsodium.
 pub const ARGON2ID_SALT_SIZE: usize = 16;
 
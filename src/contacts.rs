@@ -0,0 +1,355 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{Cursor, Read};
+use std::time::SystemTime;
+
+use base64::prelude::*;
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroizing;
+
+use crate::consts;
+use crate::crypto;
+use crate::error::Error;
+use crate::json;
+use crate::utils;
+
+
+/// `format` of every contacts file, so some other JSON file is not mistaken for one.
+const FORMAT: &str = "coldwire-contacts";
+
+/// Prefixed to the body before signing, the account key also signs the server's login challenges.
+const SIGNATURE_CONTEXT: &[u8] = b"coldwire-contacts\0";
+
+/// Start of a libcold `Contact::export_plain` blob, followed by its version byte.
+const CONTACT_BLOB_MAGIC: &[u8] = b"COLDWIREMESSENGER\0";
+
+
+/// One contact in an `export-contacts` file: who they are and the key they proved, never session secrets.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ContactEntry {
+    pub id: String,
+    #[serde(default)]
+    pub nickname: String,
+    /// `crypto::key_fingerprint` of the contact's signing key, unknown before the key exchange.
+    #[serde(default)]
+    pub fingerprint: Option<String>,
+    /// Verified on the exporting machine, an imported contact has to be verified again.
+    #[serde(default)]
+    pub verified: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Body {
+    exported_at: String,
+    contacts: Vec<ContactEntry>,
+}
+
+/// The file as written. `body` is the signed JSON kept as a string so its bytes survive a round trip,
+/// fields a newer version adds are ignored by older readers.
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+    format: String,
+    version: u32,
+    signer_key: String,
+    signature: String,
+    body: String,
+}
+
+/// A contacts file whose signature checked out.
+#[derive(Debug)]
+pub struct SignedContacts {
+    /// ML-DSA-87 public key of the account that exported the file.
+    pub signer_key: Vec<u8>,
+    pub exported_at: String,
+    pub contacts: Vec<ContactEntry>,
+}
+
+/// An imported contact we already have under another key.
+#[derive(Debug, PartialEq)]
+pub struct Conflict {
+    pub id: String,
+    pub ours: String,
+    pub theirs: String,
+}
+
+/// What `merge` did with an imported list.
+#[derive(Debug, Default)]
+pub struct Merge {
+    /// Contacts we did not have yet.
+    pub added: Vec<ContactEntry>,
+    /// Contacts we have with the same (or no) fingerprint, left alone.
+    pub known: usize,
+    /// Contacts we have with another fingerprint, our entry is kept.
+    pub conflicts: Vec<Conflict>,
+}
+
+
+/// Describe `contact` for an export, `None` if it has no id (which the client never stores).
+pub fn entry(contact: &libcold::Contact) -> Option<ContactEntry> {
+    let ad = std::str::from_utf8(contact.additional_data.as_ref()?).ok()?;
+    let id = json::extract_json_value(ad, "id")?;
+
+    // the key the contact proved wins over one an earlier import promised
+    let fingerprint = contact.export_plain().ok()
+        .and_then(|blob| contact_signing_key(&blob))
+        .map(|key| crypto::key_fingerprint(&key))
+        .or_else(|| json::extract_json_value(ad, "fingerprint"));
+
+    Some(ContactEntry {
+        id,
+        nickname: json::extract_json_value(ad, "nickname").unwrap_or_default(),
+        fingerprint,
+        verified: contact.state == libcold::ContactState::Verified,
+    })
+}
+
+/// The `additional_data` of a contact created from an imported entry, read back with `json::extract_json_value`.
+pub fn additional_data(entry: &ContactEntry) -> Zeroizing<Vec<u8>> {
+    let ad_string = match &entry.fingerprint {
+        Some(fingerprint) => format!("{{\"id\":\"{}\",\"nickname\":\"{}\",\"fingerprint\":\"{}\"}}", entry.id, entry.nickname, fingerprint),
+        None => format!("{{\"id\":\"{}\",\"nickname\":\"{}\"}}", entry.id, entry.nickname),
+    };
+
+    Zeroizing::new(ad_string.into_bytes())
+}
+
+/// The contact's signing public key from a libcold `export_plain` blob (version 1): magic, version, state and
+/// message lock bytes, then our signing public and secret keys and theirs, each as a flag byte and a
+/// big-endian u32 length. `None` before the key exchange or for a blob version this does not know.
+fn contact_signing_key(blob: &[u8]) -> Option<Vec<u8>> {
+    let rest = blob.strip_prefix(CONTACT_BLOB_MAGIC)?;
+    let mut cur = Cursor::new(rest);
+
+    let mut header = [0u8; 3];
+    cur.read_exact(&mut header).ok()?;
+    if header[0] != 1 {
+        return None;
+    }
+
+    let mut field = || -> Option<Option<Vec<u8>>> {
+        let mut present = [0u8; 1];
+        cur.read_exact(&mut present).ok()?;
+        if present[0] == 0 {
+            return Some(None);
+        }
+
+        let mut len = [0u8; 4];
+        cur.read_exact(&mut len).ok()?;
+        let len = u32::from_be_bytes(len) as usize;
+        if len > rest.len() {
+            return None;
+        }
+
+        let mut value = vec![0u8; len];
+        cur.read_exact(&mut value).ok()?;
+        Some(Some(value))
+    };
+
+    // our public key, then our secret key which is wiped right away
+    field()?;
+    let _ = field()?.map(Zeroizing::new);
+
+    field()?
+}
+
+fn signed_bytes(body: &str) -> Vec<u8> {
+    [SIGNATURE_CONTEXT, body.as_bytes()].concat()
+}
+
+
+/// Write `contacts` as a contacts file signed with the account key, see `parse`.
+pub fn export(contacts: Vec<ContactEntry>, signer_public_key: &[u8], signer_secret_key: &[u8]) -> Result<String, Error> {
+    let body = serde_json::to_string(&Body {
+        exported_at: utils::utc_timestamp(SystemTime::now()),
+        contacts,
+    }).map_err(|_| Error::FailedToSerializeContacts)?;
+
+    let signature = libcold::crypto::generate_ml_dsa_87_signature(signer_secret_key, &signed_bytes(&body))
+        .map_err(|_| Error::FailedToSignContacts)?;
+
+    let envelope = Envelope {
+        format: FORMAT.to_string(),
+        version: consts::CONTACTS_FILE_VERSION,
+        signer_key: BASE64_STANDARD.encode(signer_public_key),
+        signature: BASE64_STANDARD.encode(signature.as_slice()),
+        body,
+    };
+
+    serde_json::to_string_pretty(&envelope).map_err(|_| Error::FailedToSerializeContacts)
+}
+
+/// Read the text of the contacts file at `path`, up to `consts::MAX_CONTACTS_FILE_SIZE`.
+pub fn read(path: &str) -> Result<String, Error> {
+    let file = File::open(path).map_err(|_| Error::FailedToOpenFile)?;
+
+    let mut text = String::new();
+    file.take(consts::MAX_CONTACTS_FILE_SIZE + 1)
+        .read_to_string(&mut text)
+        .map_err(|_| Error::FailedToReadFile)?;
+
+    if text.len() as u64 > consts::MAX_CONTACTS_FILE_SIZE {
+        return Err(Error::InvalidContactsFile);
+    }
+
+    Ok(text)
+}
+
+/// Read a contacts file written by `export` and check its signature against the key it names. Who that key
+/// belongs to is up to the caller.
+pub fn parse(text: &str) -> Result<SignedContacts, Error> {
+    let envelope: Envelope = serde_json::from_str(text).map_err(|_| Error::InvalidContactsFile)?;
+
+    if envelope.format != FORMAT {
+        return Err(Error::InvalidContactsFile);
+    }
+    if envelope.version > consts::CONTACTS_FILE_VERSION {
+        return Err(Error::UnsupportedContactsFileVersion);
+    }
+
+    let signer_key = BASE64_STANDARD.decode(&envelope.signer_key).map_err(|_| Error::InvalidContactsFile)?;
+    let signature = BASE64_STANDARD.decode(&envelope.signature).map_err(|_| Error::InvalidContactsFile)?;
+
+    libcold::crypto::verify_ml_dsa_87_signature(&signer_key, &signed_bytes(&envelope.body), &signature)
+        .map_err(|_| Error::ContactsFileSignatureInvalid)?;
+
+    let body: Body = serde_json::from_str(&envelope.body).map_err(|_| Error::InvalidContactsFile)?;
+
+    let mut ids = HashSet::new();
+    for contact in &body.contacts {
+        if !is_valid(contact) || !ids.insert(contact.id.as_str()) {
+            return Err(Error::InvalidContactsFile);
+        }
+    }
+
+    Ok(SignedContacts { signer_key, exported_at: body.exported_at, contacts: body.contacts })
+}
+
+/// Ids and nicknames end up in a contact's `additional_data`, which is read without unescaping.
+fn is_valid(entry: &ContactEntry) -> bool {
+    let plain = |s: &str| !s.chars().any(|c| c == '"' || c == '\\' || c.is_control());
+
+    utils::validate_identifier(&entry.id)
+        && plain(&entry.id)
+        && plain(&entry.nickname)
+        && entry.fingerprint.as_deref().is_none_or(plain)
+}
+
+
+/// Sort `imported` into contacts to add, ones we already have and ones we have under another fingerprint.
+pub fn merge(existing: &[ContactEntry], imported: Vec<ContactEntry>) -> Merge {
+    let mut merge = Merge::default();
+
+    for entry in imported {
+        match existing.iter().find(|e| e.id == entry.id) {
+            None => merge.added.push(entry),
+            Some(ours) => match (&ours.fingerprint, &entry.fingerprint) {
+                (Some(a), Some(b)) if a != b => merge.conflicts.push(Conflict {
+                    id: entry.id,
+                    ours: a.clone(),
+                    theirs: b.clone(),
+                }),
+                _ => merge.known += 1,
+            },
+        }
+    }
+
+    merge
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(id: &str, fingerprint: Option<&str>) -> ContactEntry {
+        ContactEntry { id: id.to_string(), nickname: String::new(), fingerprint: fingerprint.map(String::from), verified: false }
+    }
+
+    fn opt(out: &mut Vec<u8>, value: Option<&[u8]>) {
+        match value {
+            None => out.push(0),
+            Some(v) => {
+                out.push(1);
+                out.extend_from_slice(&(v.len() as u32).to_be_bytes());
+                out.extend_from_slice(v);
+            }
+        }
+    }
+
+    #[test]
+    fn test_contact_signing_key() {
+        let mut blob = CONTACT_BLOB_MAGIC.to_vec();
+        blob.extend_from_slice(&[1, 4, 0]);
+        opt(&mut blob, Some(b"our public"));
+        opt(&mut blob, Some(b"our secret"));
+        opt(&mut blob, Some(b"their public"));
+        opt(&mut blob, None);
+
+        assert_eq!(contact_signing_key(&blob), Some(b"their public".to_vec()));
+
+        let mut pending = CONTACT_BLOB_MAGIC.to_vec();
+        pending.extend_from_slice(&[1, 0, 0]);
+        opt(&mut pending, None);
+        opt(&mut pending, None);
+        opt(&mut pending, None);
+        assert_eq!(contact_signing_key(&pending), None);
+
+        blob[CONTACT_BLOB_MAGIC.len()] = 2;
+        assert_eq!(contact_signing_key(&blob), None);
+        assert_eq!(contact_signing_key(&blob[..CONTACT_BLOB_MAGIC.len() + 5]), None);
+    }
+
+    #[test]
+    fn test_export_parse_round_trip() {
+        let (pk, sk) = libcold::crypto::generate_ml_dsa_87_keypair().unwrap();
+        let contacts = vec![entry("1234567890123456", Some("sha256/abc=")), entry("1234@relay.example.org", None)];
+
+        let text = export(contacts.clone(), &pk, &sk).unwrap();
+        let parsed = parse(&text).unwrap();
+        assert_eq!(parsed.contacts, contacts);
+        assert_eq!(parsed.signer_key, pk.to_vec());
+
+        let tampered = text.replace("1234567890123456", "6543210987654321");
+        assert!(matches!(parse(&tampered), Err(Error::ContactsFileSignatureInvalid)));
+
+        let newer = text.replace(&format!("\"version\": {}", consts::CONTACTS_FILE_VERSION), "\"version\": 99");
+        assert!(matches!(parse(&newer), Err(Error::UnsupportedContactsFileVersion)));
+
+        assert!(matches!(parse("{\"format\":\"something-else\"}"), Err(Error::InvalidContactsFile)));
+    }
+
+    #[test]
+    fn test_merge() {
+        let existing = vec![entry("1111111111111111", Some("sha256/a=")), entry("2222222222222222", None)];
+        let imported = vec![
+            entry("1111111111111111", Some("sha256/b=")),
+            entry("2222222222222222", Some("sha256/c=")),
+            entry("3333333333333333", None),
+        ];
+
+        let merge = merge(&existing, imported);
+        assert_eq!(merge.added, vec![entry("3333333333333333", None)]);
+        assert_eq!(merge.known, 1);
+        assert_eq!(merge.conflicts, vec![Conflict {
+            id: String::from("1111111111111111"),
+            ours: String::from("sha256/a="),
+            theirs: String::from("sha256/b="),
+        }]);
+    }
+
+    #[test]
+    fn test_is_valid() {
+        assert!(is_valid(&entry("1234567890123456", None)));
+        assert!(!is_valid(&entry("12345", None)));
+        assert!(!is_valid(&entry("1234@relay\".example.org", None)));
+
+        let mut nickname = entry("1234567890123456", None);
+        nickname.nickname = String::from("bob\",\"id\":\"1");
+        assert!(!is_valid(&nickname));
+
+        assert_eq!(
+            additional_data(&entry("1234567890123456", Some("sha256/a="))).as_slice(),
+            b"{\"id\":\"1234567890123456\",\"nickname\":\"\",\"fingerprint\":\"sha256/a=\"}"
+        );
+    }
+}
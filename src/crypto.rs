@@ -6,6 +6,8 @@ use chacha20poly1305::{
     aead::{Aead, KeyInit},
     XChaCha20Poly1305, XNonce, Key
 };
+use base64::prelude::*;
+use sha2::{Digest, Sha256};
 use zeroize::{Zeroize, Zeroizing};
 use libcold;
 
@@ -118,6 +120,25 @@ pub fn decrypt_xchacha20poly1305(key_bytes: &Zeroizing<Vec<u8>>, nonce_bytes: &[
 }
 
 
+/// SHA-256 of a DER encoded SubjectPublicKeyInfo, the value `--pin-sha256` pins.
+pub fn spki_sha256(spki_der: &[u8]) -> [u8; 32] {
+    Sha256::digest(spki_der).into()
+}
+
+
+/// How `inspect` shows a public key without printing it: "sha256/" and the base64 of its SHA-256, like a pin.
+pub fn key_fingerprint(public_key: &[u8]) -> String {
+    format!("sha256/{}", BASE64_STANDARD.encode(Sha256::digest(public_key)))
+}
+
+
+/// True if the leaf certificate's SPKI hashes to one of `pins`.
+pub fn spki_matches_pins(pins: &[[u8; 32]], spki_der: &[u8]) -> bool {
+    let hash = spki_sha256(spki_der);
+
+    pins.iter().any(|pin| libcold::crypto::compare_secrets(pin, &hash))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -229,4 +250,16 @@ mod tests {
 
     }
 
+    #[test]
+    fn test_spki_pins() {
+        let hash = spki_sha256(b"abc");
+        assert_eq!(
+            hash.iter().map(|b| format!("{:02x}", b)).collect::<String>(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+
+        assert!(spki_matches_pins(&[[0u8; 32], hash], b"abc"));
+        assert!(!spki_matches_pins(&[hash], b"abd"));
+        assert!(!spki_matches_pins(&[], b"abc"));
+    }
 }
@@ -0,0 +1,236 @@
+use std::io;
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ureq::unversioned::transport::{ConnectionDetails, Connector};
+
+use crate::bind;
+use crate::trace;
+use crate::transport::StreamTransport;
+
+
+/// How long an attempt gets before the next address is tried alongside it, RFC 8305 recommends 250 ms.
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+
+/// Connect to the first of `addrs` that answers, racing them Happy Eyeballs style (RFC 8305).
+///
+/// The addresses are interleaved by family, starting with the family the resolver listed first, and every
+/// `CONNECTION_ATTEMPT_DELAY` (or as soon as an attempt fails) the next one is started next to the ones
+/// still pending. A dead IPv6 path then costs 250 ms instead of a whole connect timeout. With `local`
+/// (`--bind-address`) only the addresses of its family are tried, from that address.
+///
+/// The error of the last attempt is returned if none succeeds, with the address prefixed to its message.
+pub(crate) fn connect(addrs: &[SocketAddr], local: Option<IpAddr>, timeout: Option<Duration>) -> io::Result<TcpStream> {
+    let addrs = interleave(addrs, local);
+    if addrs.is_empty() {
+        return Err(match local {
+            Some(local) => io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                format!("no address of the server can be reached from the bind address {}", local),
+            ),
+            None => io::Error::new(io::ErrorKind::AddrNotAvailable, "no addresses to connect to"),
+        });
+    }
+
+    let deadline = timeout.map(|t| Instant::now() + t);
+    let (sender, receiver) = mpsc::channel();
+
+    let mut started = 0;
+    let mut failed = 0;
+    let mut last_error = None;
+
+    loop {
+        if started < addrs.len() {
+            let addr = addrs[started];
+            let sender = sender.clone();
+            let attempt_timeout = deadline.map(|d| d.saturating_duration_since(Instant::now()));
+
+            // a losing attempt finishes on its own, its stream is dropped when nobody receives it
+            thread::spawn(move || {
+                let result = connect_one(addr, local, attempt_timeout)
+                    .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", addr, e)));
+                let _ = sender.send(result);
+            });
+            started += 1;
+        }
+
+        let wait = if started < addrs.len() {
+            Some(CONNECTION_ATTEMPT_DELAY)
+        } else {
+            deadline.map(|d| d.saturating_duration_since(Instant::now()))
+        };
+
+        let result = match wait {
+            Some(wait) => match receiver.recv_timeout(wait) {
+                Ok(result) => result,
+                // start the next attempt next to the pending ones
+                Err(RecvTimeoutError::Timeout) if started < addrs.len() => continue,
+                Err(_) => return Err(last_error.unwrap_or_else(|| io::Error::from(io::ErrorKind::TimedOut))),
+            },
+            None => match receiver.recv() {
+                Ok(result) => result,
+                Err(_) => return Err(last_error.unwrap_or_else(|| io::Error::from(io::ErrorKind::TimedOut))),
+            },
+        };
+
+        match result {
+            Ok(stream) => return Ok(stream),
+            Err(e) => {
+                failed += 1;
+                last_error = Some(e);
+
+                if failed == addrs.len() {
+                    return Err(last_error.unwrap());
+                }
+            }
+        }
+    }
+}
+
+
+fn connect_one(addr: SocketAddr, local: Option<IpAddr>, timeout: Option<Duration>) -> io::Result<TcpStream> {
+    match (local, timeout) {
+        (_, Some(timeout)) if timeout.is_zero() => Err(io::Error::from(io::ErrorKind::TimedOut)),
+        (Some(local), _) => bind::connect_from(local, addr, timeout),
+        (None, Some(timeout)) => TcpStream::connect_timeout(&addr, timeout),
+        (None, None) => TcpStream::connect(addr),
+    }
+}
+
+
+/// Alternate the address families, starting with the family of the first address (RFC 8305 section 4).
+/// Addresses `local` can't reach are dropped.
+pub(crate) fn interleave(addrs: &[SocketAddr], local: Option<IpAddr>) -> Vec<SocketAddr> {
+    let usable = addrs.iter().copied().filter(|a| local.is_none_or(|l| l.is_ipv4() == a.is_ipv4()));
+
+    let Some(first) = usable.clone().next() else {
+        return Vec::new();
+    };
+
+    let (mut preferred, mut other): (Vec<SocketAddr>, Vec<SocketAddr>) = usable.partition(|a| a.is_ipv4() == first.is_ipv4());
+    preferred.reverse();
+    other.reverse();
+
+    let mut out = Vec::with_capacity(preferred.len() + other.len());
+    while !preferred.is_empty() || !other.is_empty() {
+        out.extend(preferred.pop());
+        out.extend(other.pop());
+    }
+
+    out
+}
+
+
+/// First link of the connector chain for direct connections, in place of ureq's `TcpConnector` which
+/// tries the addresses one after the other. TLS is chained on top of it like in the default chain.
+#[derive(Debug)]
+pub(crate) struct DirectConnector {
+    /// From `--bind-address`.
+    local: Option<IpAddr>,
+}
+
+impl DirectConnector {
+    pub(crate) fn new(local: Option<IpAddr>) -> Self {
+        DirectConnector { local }
+    }
+}
+
+impl Connector<()> for DirectConnector {
+    type Out = StreamTransport;
+
+    fn connect(&self, details: &ConnectionDetails, _chained: Option<()>) -> Result<Option<Self::Out>, ureq::Error> {
+        // ureq leaves the addresses to the proxy when one is configured, we never go through one here
+        let addrs: Vec<SocketAddr> = if details.addrs.is_empty() {
+            details.resolver.resolve(details.uri, details.config, details.timeout)?.iter().copied().collect()
+        } else {
+            details.addrs.iter().copied().collect()
+        };
+
+        let span = tracing::debug_span!("connect", server = %trace::uri_target(&details.uri), peer = tracing::field::Empty);
+        let _entered = span.enter();
+
+        match connect(&addrs, self.local, details.timeout.not_zero().map(|t| *t)) {
+            Ok(stream) => {
+                if let Ok(peer) = stream.peer_addr() {
+                    span.record("peer", tracing::field::display(peer));
+                }
+                Ok(Some(StreamTransport::new(stream, details.config)?))
+            }
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => Err(ureq::Error::Timeout(details.timeout.reason)),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr, TcpListener};
+
+    fn v4(port: u16) -> SocketAddr {
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port)
+    }
+
+    fn v6(port: u16) -> SocketAddr {
+        SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), port)
+    }
+
+    #[test]
+    fn test_interleave() {
+        assert_eq!(interleave(&[v6(1), v6(2), v4(3), v4(4), v4(5)], None), vec![v6(1), v4(3), v6(2), v4(4), v4(5)]);
+        assert_eq!(interleave(&[v4(1), v6(2), v6(3)], None), vec![v4(1), v6(2), v6(3)]);
+        assert_eq!(interleave(&[v6(1), v4(2), v6(3)], Some(IpAddr::V4(Ipv4Addr::LOCALHOST))), vec![v4(2)]);
+        assert!(interleave(&[v6(1)], Some(IpAddr::V4(Ipv4Addr::LOCALHOST))).is_empty());
+    }
+
+    #[test]
+    fn test_connect_skips_dead_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let live = listener.local_addr().unwrap();
+        // bind then drop to get a port nothing listens on
+        let dead = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+
+        let stream = connect(&[dead, live], None, Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), live);
+    }
+
+    #[test]
+    fn test_connect_all_refused() {
+        let dead = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+
+        let err = connect(&[dead], None, Some(Duration::from_secs(5))).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+        assert!(err.to_string().starts_with(&format!("{}: ", dead)));
+
+        let err = connect(&[dead], Some(IpAddr::V6(Ipv6Addr::LOCALHOST)), None).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrNotAvailable);
+    }
+
+    #[test]
+    fn test_connector_resolves_without_addresses() {
+        use std::io::{Read, Write};
+        use ureq::Agent;
+        use ureq::unversioned::resolver::DefaultResolver;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://localhost:{}/", listener.local_addr().unwrap().port());
+        let server = thread::spawn(move || {
+            let (mut s, _) = listener.accept().unwrap();
+            let mut head = [0u8; 16];
+            s.read_exact(&mut head).unwrap();
+            s.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").unwrap();
+        });
+
+        // with a proxy configured ureq leaves the target unresolved, the connector has to look it up
+        let proxy = ureq::Proxy::new("http://127.0.0.1:9").unwrap();
+        let config = Agent::config_builder().proxy(Some(proxy)).build();
+        let agent = Agent::with_parts(config, DirectConnector::new(None), DefaultResolver::default());
+
+        assert_eq!(agent.get(&url).call().unwrap().status(), 200);
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,386 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Read;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use base64::prelude::*;
+use ureq::Agent;
+use ureq::http::Uri;
+use ureq::tls::RootCerts;
+use ureq::unversioned::resolver::{DefaultResolver, ResolvedSocketAddrs, Resolver};
+use ureq::unversioned::transport::{Connector, NextTimeout};
+
+use crate::direct::DirectConnector;
+use crate::requests::TlsVersion;
+use crate::tls;
+use crate::transport::TlsConnector;
+
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+
+/// Don't trust a TTL longer than a day, a relay that moved should not stay unreachable until restart.
+const MAX_TTL_SECS: u32 = 24 * 60 * 60;
+
+/// DNS messages over HTTPS are small, anything bigger is not an answer to our question.
+const MAX_RESPONSE_SIZE: u64 = 64 * 1024;
+
+/// ureq keeps at most this many addresses per lookup.
+const MAX_ADDRS: usize = 16;
+
+
+/// Answers already looked up in this process, by lowercase hostname, with the instant they expire.
+static CACHE: OnceLock<Mutex<HashMap<String, (Vec<IpAddr>, Instant)>>> = OnceLock::new();
+
+
+/// Resolves hostnames with DNS-over-HTTPS (RFC 8484) instead of the system resolver, set by `--doh`.
+///
+/// The DoH server's own hostname is still resolved by the system resolver, give it as an IP
+/// address (e.g. https://9.9.9.9/dns-query) to avoid that lookup too.
+///
+/// The queries are always direct connections: the resolver only serves direct connections to the relay
+/// in the first place, and ALL_PROXY / HTTPS_PROXY from the environment are ignored like for those.
+#[derive(Clone)]
+pub struct DohResolver {
+    url: String,
+    root_certs: Option<RootCerts>,
+    timeout: Duration,
+    user_agent: String,
+    /// `--bind-address`, the queries are direct connections too.
+    bind_address: Option<IpAddr>,
+    /// `--min-tls`, the DoH server is an https server like any other.
+    min_tls: TlsVersion,
+}
+
+impl fmt::Debug for DohResolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DohResolver")
+            .field("url", &self.url)
+            .field("timeout", &self.timeout)
+            .finish()
+    }
+}
+
+
+impl DohResolver {
+    pub fn new(url: &str, root_certs: Option<RootCerts>, timeout: Duration, user_agent: &str) -> Self {
+        DohResolver { url: url.to_string(), root_certs, timeout, user_agent: user_agent.to_string(), bind_address: None, min_tls: TlsVersion::Tls12 }
+    }
+
+    /// Send the queries from `bind_address`.
+    pub fn bind_address(mut self, bind_address: Option<IpAddr>) -> Self {
+        self.bind_address = bind_address;
+        self
+    }
+
+    /// Refuse a DoH server older than `min_tls`.
+    pub fn min_tls(mut self, min_tls: TlsVersion) -> Self {
+        self.min_tls = min_tls;
+        self
+    }
+
+    /// The A and AAAA addresses of `host`, IPv4 first. IP literals are returned as is.
+    pub fn lookup(&self, host: &str) -> Result<Vec<IpAddr>, String> {
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(vec![ip]);
+        }
+
+        let key = host.to_ascii_lowercase();
+        let cache = CACHE.get_or_init(|| Mutex::new(HashMap::new()));
+
+        if let Some((addrs, expires)) = cache.lock().unwrap().get(&key) {
+            if Instant::now() < *expires {
+                return Ok(addrs.clone());
+            }
+        }
+
+        let mut addrs = Vec::new();
+        let mut ttl = MAX_TTL_SECS;
+        let mut errors = Vec::new();
+
+        for qtype in [TYPE_A, TYPE_AAAA] {
+            match self.query(&key, qtype) {
+                Ok((found, found_ttl)) => {
+                    if !found.is_empty() {
+                        ttl = ttl.min(found_ttl);
+                    }
+                    addrs.extend(found);
+                }
+                Err(e) => errors.push(e),
+            }
+        }
+
+        if addrs.is_empty() {
+            return Err(match errors.first() {
+                Some(e) => format!("{}: {}", host, e),
+                None => format!("{}: no A or AAAA records", host),
+            });
+        }
+
+        let expires = Instant::now() + Duration::from_secs(u64::from(ttl));
+        cache.lock().unwrap().insert(key, (addrs.clone(), expires));
+
+        Ok(addrs)
+    }
+
+    fn query(&self, host: &str, qtype: u16) -> Result<(Vec<IpAddr>, u32), String> {
+        let message = build_query(host, qtype)?;
+
+        // the relay's --pin-sha256 pins don't apply to the DoH server
+        let tls = tls::client_config(self.root_certs.as_ref(), false, self.min_tls, &[])
+            .map_err(|e| format!("Failed to set up TLS for DoH server {}: {:?}", self.url, e))?;
+
+        let config = Agent::config_builder()
+            .http_status_as_error(false)
+            .timeout_global(Some(self.timeout))
+            .user_agent(self.user_agent.as_str())
+            .proxy(None)
+            .build();
+        let agent = Agent::with_parts(config, DirectConnector::new(self.bind_address).chain(TlsConnector::new(tls)), DefaultResolver::default());
+
+        let mut response = agent.get(&self.url)
+            .query("dns", BASE64_URL_SAFE_NO_PAD.encode(&message))
+            .header("accept", "application/dns-message")
+            .call()
+            .map_err(|e| format!("DoH request to {} failed: {}", self.url, e))?;
+
+        if response.status() != 200 {
+            return Err(format!("DoH server {} answered with status {}", self.url, response.status()));
+        }
+
+        let mut body = Vec::new();
+        response.body_mut()
+            .as_reader()
+            .take(MAX_RESPONSE_SIZE)
+            .read_to_end(&mut body)
+            .map_err(|e| format!("Failed to read the DoH response from {}: {}", self.url, e))?;
+
+        parse_response(&body, qtype)
+    }
+}
+
+
+impl Resolver for DohResolver {
+    fn resolve(&self, uri: &Uri, _config: &ureq::config::Config, _timeout: NextTimeout) -> Result<ResolvedSocketAddrs, ureq::Error> {
+        let host = uri.host().ok_or(ureq::Error::HostNotFound)?;
+        let port = uri.port_u16().unwrap_or(if uri.scheme_str() == Some("https") { 443 } else { 80 });
+
+        let addrs = self.lookup(host).map_err(|_| ureq::Error::HostNotFound)?;
+
+        let mut result = self.empty();
+        for ip in addrs.into_iter().take(MAX_ADDRS) {
+            result.push(SocketAddr::new(ip, port));
+        }
+
+        Ok(result)
+    }
+}
+
+
+/// Encode a recursive query for `host` in DNS wire format. The ID is 0 as RFC 8484 recommends for caching.
+fn build_query(host: &str, qtype: u16) -> Result<Vec<u8>, String> {
+    let mut message = vec![
+        0, 0, // ID
+        0x01, 0x00, // flags: recursion desired
+        0, 1, // one question
+        0, 0, 0, 0, 0, 0, // no answer, authority or additional records
+    ];
+
+    let name = host.trim_end_matches('.');
+    if name.is_empty() || name.len() > 253 {
+        return Err(format!("invalid hostname '{}'", host));
+    }
+
+    for label in name.split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(format!("invalid hostname '{}'", host));
+        }
+        message.push(label.len() as u8);
+        message.extend_from_slice(label.as_bytes());
+    }
+    message.push(0);
+
+    message.extend_from_slice(&qtype.to_be_bytes());
+    message.extend_from_slice(&CLASS_IN.to_be_bytes());
+
+    Ok(message)
+}
+
+
+/// Pull the `qtype` addresses and their smallest TTL out of a DNS response. CNAME records on
+/// the way are skipped, the resolver already followed them.
+fn parse_response(message: &[u8], qtype: u16) -> Result<(Vec<IpAddr>, u32), String> {
+    let u16_at = |pos: usize| -> Result<u16, String> {
+        message.get(pos..pos + 2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]))
+            .ok_or_else(|| String::from("truncated DNS response"))
+    };
+
+    let flags = u16_at(2)?;
+    if flags & 0x8000 == 0 {
+        return Err(String::from("DNS message is not a response"));
+    }
+    match flags & 0x000f {
+        0 => {}
+        3 => return Err(String::from("no such domain (NXDOMAIN)")),
+        rcode => return Err(format!("DNS server returned error code {}", rcode)),
+    }
+
+    let questions = u16_at(4)?;
+    let answers = u16_at(6)?;
+
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = skip_name(message, pos)? + 4;
+    }
+
+    let mut addrs = Vec::new();
+    let mut ttl = MAX_TTL_SECS;
+
+    for _ in 0..answers {
+        pos = skip_name(message, pos)?;
+
+        let rtype = u16_at(pos)?;
+        let class = u16_at(pos + 2)?;
+        let record_ttl = ((u16_at(pos + 4)? as u32) << 16) | u16_at(pos + 6)? as u32;
+        let len = u16_at(pos + 8)? as usize;
+        pos += 10;
+
+        let data = message.get(pos..pos + len)
+            .ok_or_else(|| String::from("truncated DNS response"))?;
+        pos += len;
+
+        if class != CLASS_IN || rtype != qtype {
+            continue;
+        }
+
+        let ip = match (rtype, data.len()) {
+            (TYPE_A, 4) => IpAddr::V4(Ipv4Addr::new(data[0], data[1], data[2], data[3])),
+            (TYPE_AAAA, 16) => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(data);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            }
+            _ => return Err(String::from("malformed address record in DNS response")),
+        };
+
+        addrs.push(ip);
+        ttl = ttl.min(record_ttl);
+    }
+
+    Ok((addrs, ttl))
+}
+
+
+/// Return the position right after the (possibly compressed) name starting at `pos`.
+fn skip_name(message: &[u8], mut pos: usize) -> Result<usize, String> {
+    loop {
+        let len = *message.get(pos).ok_or_else(|| String::from("truncated DNS response"))? as usize;
+
+        match len {
+            0 => return Ok(pos + 1),
+            // a compression pointer always ends the name
+            l if l & 0xc0 == 0xc0 => return Ok(pos + 2),
+            l if l & 0xc0 != 0 => return Err(String::from("malformed name in DNS response")),
+            l => pos += 1 + l,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A response to `build_query("relay.example.org", qtype)` with a CNAME then the given records.
+    fn response(qtype: u16, records: &[(&[u8], u32)]) -> Vec<u8> {
+        let mut query = build_query("relay.example.org", qtype).unwrap();
+        query[2] = 0x81;
+        query[3] = 0x80;
+        query[7] = records.len() as u8 + 1;
+
+        // CNAME relay.example.org -> pointer back at the question name
+        query.extend_from_slice(&[0xc0, 12]);
+        query.extend_from_slice(&5u16.to_be_bytes());
+        query.extend_from_slice(&CLASS_IN.to_be_bytes());
+        query.extend_from_slice(&60u32.to_be_bytes());
+        query.extend_from_slice(&2u16.to_be_bytes());
+        query.extend_from_slice(&[0xc0, 12]);
+
+        for (data, ttl) in records {
+            query.extend_from_slice(&[0xc0, 12]);
+            query.extend_from_slice(&qtype.to_be_bytes());
+            query.extend_from_slice(&CLASS_IN.to_be_bytes());
+            query.extend_from_slice(&ttl.to_be_bytes());
+            query.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            query.extend_from_slice(data);
+        }
+
+        query
+    }
+
+    #[test]
+    fn test_build_query() {
+        let query = build_query("relay.example.org", TYPE_A).unwrap();
+
+        assert_eq!(&query[..12], &[0, 0, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(&query[12..], b"\x05relay\x07example\x03org\x00\x00\x01\x00\x01");
+
+        assert!(build_query("a..b", TYPE_A).is_err());
+        assert!(build_query(&"a".repeat(64), TYPE_A).is_err());
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let message = response(TYPE_A, &[(&[192, 0, 2, 1], 300), (&[192, 0, 2, 2], 120)]);
+        let (addrs, ttl) = parse_response(&message, TYPE_A).unwrap();
+
+        assert_eq!(addrs, vec!["192.0.2.1".parse::<IpAddr>().unwrap(), "192.0.2.2".parse().unwrap()]);
+        assert_eq!(ttl, 120);
+
+        let ipv6 = "2001:db8::1".parse::<Ipv6Addr>().unwrap().octets();
+        let message = response(TYPE_AAAA, &[(&ipv6, 60)]);
+        assert_eq!(parse_response(&message, TYPE_AAAA).unwrap().0, vec!["2001:db8::1".parse::<IpAddr>().unwrap()]);
+    }
+
+    #[test]
+    fn test_parse_response_errors() {
+        let mut message = response(TYPE_A, &[]);
+        message[3] = 0x83;
+        assert!(parse_response(&message, TYPE_A).unwrap_err().contains("NXDOMAIN"));
+
+        let message = response(TYPE_A, &[(&[192, 0, 2, 1], 300)]);
+        assert!(parse_response(&message[..message.len() - 2], TYPE_A).unwrap_err().contains("truncated"));
+
+        let message = response(TYPE_A, &[(&[192, 0, 2], 300)]);
+        assert!(parse_response(&message, TYPE_A).unwrap_err().contains("malformed"));
+
+        assert!(parse_response(&build_query("relay.example.org", TYPE_A).unwrap(), TYPE_A).unwrap_err().contains("not a response"));
+    }
+
+    #[test]
+    fn test_lookup_ip_literal_skips_doh() {
+        let resolver = DohResolver::new("https://doh.invalid/dns-query", None, Duration::from_secs(1), "coldwire-desktop/test");
+
+        assert_eq!(resolver.lookup("127.0.0.1").unwrap(), vec![IpAddr::V4(Ipv4Addr::LOCALHOST)]);
+        assert_eq!(resolver.lookup("[::1]").unwrap(), vec![IpAddr::V6(Ipv6Addr::LOCALHOST)]);
+    }
+
+    #[test]
+    fn test_lookup_uses_cache() {
+        let resolver = DohResolver::new("https://doh.invalid/dns-query", None, Duration::from_secs(1), "coldwire-desktop/test");
+        let addrs = vec!["192.0.2.7".parse::<IpAddr>().unwrap()];
+
+        CACHE.get_or_init(|| Mutex::new(HashMap::new()))
+            .lock()
+            .unwrap()
+            .insert(String::from("cached.example.org"), (addrs.clone(), Instant::now() + Duration::from_secs(60)));
+
+        // doh.invalid never resolves, so this only succeeds from the cache
+        assert_eq!(resolver.lookup("Cached.Example.org").unwrap(), addrs);
+    }
+}
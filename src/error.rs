@@ -1,18 +1,71 @@
+use std::time::Duration;
+
+use serde_json::{Map, Value};
+
+use crate::completions::Shell;
+use crate::consts;
+
+
+/// Process exit codes, so scripts can tell failure classes apart.
+pub mod exit_code {
+    /// Success, also used for `--help` and `--version`.
+    pub const SUCCESS: i32 = 0;
+    /// Anything not covered by a more specific code.
+    pub const FAILURE: i32 = 1;
+    /// Invalid command-line arguments, environment variables or config file.
+    pub const USAGE: i32 = 2;
+    /// The proxy or the server could not be reached, or the server misbehaved.
+    pub const CONNECTION: i32 = 3;
+    /// The state file could not be read, decrypted, locked or written.
+    pub const STATE_FILE: i32 = 4;
+    /// The user declined a confirmation prompt.
+    pub const ABORTED: i32 = 5;
+}
+
+
 #[derive(Debug)]
 pub enum Error {
     FailedToReadLine,
     FailedToFlush,
     FailedToOpenFile,
     FailedToCreateFile,
+    FailedToCreateDirectory,
+    ParentPathIsNotADirectory,
     FailedToGetFileMetadata,
     FailedToReadFile,
     FailedToWriteToFile,
     FailedToSeekInFile,
+    FailedToSyncFile,
+    FailedToReplaceFile,
+    FailedToDeleteFile,
 
     InvalidServerUrl,
+    PlaintextServerUrlNotAllowed,
+    /// `--onion-only` is set and the server (e.g. from the state file) is not a .onion address.
+    NonOnionServerNotAllowed,
+    /// The server's certificate matched none of the `--pin-sha256` pins.
+    CertificatePinMismatch,
+    /// `--strict` is set and the server is reached through plain SOCKS5, which resolves its name locally.
+    Socks5DnsLeakNotAllowed,
+    /// The rustls client config could not be built from the roots and `--min-tls`.
+    FailedToConfigureTls,
+    /// ureq refused the proxy URL built from `ProxyInfo`.
+    FailedToConfigureProxy,
     FailedToGenerateSecureRandomBytes,
     Argon2IdHashingError,
     InvalidStateFile,
+    /// `inspect` or `wipe` was given no state file, or one that does not exist.
+    StateFileNotFound,
+    /// The state file did not decrypt: wrong password, or the file was tampered with.
+    WrongStateFilePasswordOrTampered,
+    StateFilePermissionsTooOpen,
+    /// Another process holds the state file lock.
+    StateFileLocked,
+    FailedToLockFile,
+    FailedToOpenLogFile,
+    FailedToInstallSignalHandler,
+    /// `--metrics-addr` could not be bound, e.g. the port is in use.
+    FailedToStartMetricsServer,
 
     InvalidXChaCha20PaddingLength,
     InvalidXChaCha20KeyLength,
@@ -28,11 +81,39 @@ pub enum Error {
     FailedToSplitLineOnce,
 
     StateFileCorrupted,
+    /// The state file was written by a newer client.
+    UnsupportedStateFileVersion,
+    FailedToBackupStateFile,
+    /// The `import-contacts` file is not a contacts file or lists an invalid contact.
+    InvalidContactsFile,
+    /// The contacts file was written by a newer client.
+    UnsupportedContactsFileVersion,
+    /// The contacts file does not match its signature, it was changed after the export.
+    ContactsFileSignatureInvalid,
+    /// `--strict` is set and the contacts file was signed by another account.
+    ContactsFileFromAnotherAccount,
+    FailedToSerializeContacts,
+    FailedToSignContacts,
+    /// `--queue-max` messages are already waiting for the relay.
+    OutboundQueueFull,
+    FailedToSerializeConfig,
 
     FailedToSendRequest,
     FailedToSendRequestBody,
+    /// The request got no answer within `--timeout`.
+    RequestTimedOut,
+    /// The relay answered 429 or 503, with the delay it asked for (`Retry-After` or a "retry in N"
+    /// body) when it gave one.
+    ServerBusy(Option<Duration>),
+    /// The HTTP proxy answered the CONNECT with 407, or the SOCKS5 proxy rejected the username/password
+    /// or wanted credentials we don't have.
+    ProxyAuthenticationRequired,
+    /// The HTTP proxy answered the CONNECT with anything else than 200, or the SOCKS5 CONNECT failed.
+    ProxyRefusedConnect,
 
     FailedToReadResponseBody,
+    /// The response body was larger than `--max-response-bytes`, the read was aborted.
+    ResponseTooLarge,
     InvalidRequestMetadata,
 
     FailedToGenerateAuthKeypair,
@@ -60,3 +141,434 @@ pub enum Error {
     FailedToUnlockMessage
 
 }
+
+
+impl Error {
+    /// The `exit_code` class of this error.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::FailedToOpenFile
+            | Error::FailedToCreateFile
+            | Error::FailedToCreateDirectory
+            | Error::ParentPathIsNotADirectory
+            | Error::FailedToGetFileMetadata
+            | Error::FailedToReadFile
+            | Error::FailedToWriteToFile
+            | Error::FailedToSeekInFile
+            | Error::FailedToSyncFile
+            | Error::FailedToReplaceFile
+            | Error::FailedToDeleteFile
+            | Error::InvalidStateFile
+            | Error::StateFileNotFound
+            | Error::WrongStateFilePasswordOrTampered
+            | Error::StateFilePermissionsTooOpen
+            | Error::StateFileLocked
+            | Error::FailedToLockFile
+            | Error::StateFileTooLargeToReadIntoMemory
+            | Error::StateFileCorrupted
+            | Error::UnsupportedStateFileVersion
+            | Error::FailedToBackupStateFile => exit_code::STATE_FILE,
+
+            Error::InvalidServerUrl
+            | Error::PlaintextServerUrlNotAllowed
+            | Error::NonOnionServerNotAllowed
+            | Error::CertificatePinMismatch
+            | Error::Socks5DnsLeakNotAllowed
+            | Error::FailedToSendRequest
+            | Error::FailedToSendRequestBody
+            | Error::RequestTimedOut
+            | Error::ServerBusy(_)
+            | Error::ProxyAuthenticationRequired
+            | Error::ProxyRefusedConnect
+            | Error::FailedToReadResponseBody
+            | Error::ResponseTooLarge
+            | Error::InvalidServerResponse
+            | Error::MalformedServerResponse
+            | Error::InvalidJsonInServerResponse
+            | Error::NonSuccessServerStatus => exit_code::CONNECTION,
+
+            _ => exit_code::FAILURE,
+        }
+    }
+
+    /// True for a failure to reach the server or proxy that may go away on its own, e.g. a Tor circuit
+    /// that broke mid-request. `Config::with_reconnect` retries these for idempotent requests, a send
+    /// that fails with one is queued instead.
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            Error::FailedToSendRequest | Error::FailedToSendRequestBody | Error::FailedToReadResponseBody | Error::RequestTimedOut
+                | Error::ServerBusy(_)
+        )
+    }
+
+    /// The delay the relay asked for before the next attempt, see `requests::ReconnectPolicy::backoff_delay`.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Error::ServerBusy(hint) => *hint,
+            _ => None,
+        }
+    }
+
+    /// `--error-format json`: `{"error":"FailedToSendRequest","message":"FailedToSendRequest"}`.
+    pub fn to_json(&self) -> String {
+        let name = variant_name(self);
+        error_json(&name, &name, None, None)
+    }
+}
+
+
+/// Errors returned while parsing the command line, environment and config file.
+#[derive(Debug)]
+pub enum ConfigError {
+    /// `--help` was given, not a failure.
+    HelpRequested,
+    /// `--version` was given, not a failure.
+    VersionRequested,
+    /// The `setup` subcommand was given, main runs the first-run wizard.
+    SetupRequested,
+    /// The `completions <shell>` subcommand was given, main prints the script.
+    CompletionsRequested(Shell),
+
+    UnknownArg(String),
+    MissingValue(&'static str),
+    UnexpectedValue(String),
+    ConflictingFlags(&'static str, &'static str),
+    DuplicateFlag(&'static str),
+    /// The first flag only makes sense together with the second.
+    RequiresFlag(&'static str, &'static str),
+    InvalidFormat(String),
+    InvalidShell(String),
+    InvalidProfile(String),
+
+    InvalidEnvValue { name: String, value: String },
+    ConfigFile(String),
+    /// The `--config` file is not valid TOML or holds a key we don't know, `reason` names the line.
+    InvalidConfigFile { path: String, reason: String },
+    /// `--profile` names no `[profile.<name>]` table of the config file.
+    ProfileNotFound { path: String, profile: String, available: Vec<String> },
+    ResponseFile(String),
+    ServerFile(String),
+
+    InvalidProxyType(String),
+    InvalidProxyAddr { input: String, reason: String },
+    /// The input is not kept as it may contain the proxy password.
+    InvalidProxyUrl { reason: String },
+    ProxyPassword(String),
+    /// A password was combined with a SOCKS4 proxy.
+    Socks4Password,
+    /// HTTP proxy credentials that can't go into the CONNECT request as they are.
+    HttpProxyCredentials(&'static str),
+    StatePassword(String),
+
+    InvalidServerUrl { index: usize, input: String, reason: String },
+    /// A URL `clean_server_url` rejected, `InvalidServerUrl` also names which `--server` entry it was.
+    MalformedServerUrl { input: String, reason: String },
+    InvalidHost { input: String, reason: String },
+    InvalidPort { input: String, reason: String },
+    InvalidDefaultServerPort { input: String, reason: String },
+    InvalidTimeout(String),
+    InvalidReconnect(String),
+    InvalidPin(String),
+    CaFile(String),
+    InvalidDohUrl(String),
+    InvalidUserAgent(String),
+    InvalidQueueMax(String),
+    InvalidConnectRetries(String),
+    InvalidSamples(String),
+    InvalidAutosave(String),
+    InvalidStateFormat(String),
+    InvalidMinTls(String),
+    InvalidMaxResponseBytes(String),
+    InvalidBindAddress(String),
+    InvalidMetricsAddr(String),
+    InvalidErrorFormat(String),
+    /// Why the state file path can not name a file, see `utils::check_state_file_path`.
+    StateFilePath(&'static str),
+    /// The `--isolate-circuits` secret could not be drawn from the system's secure random source.
+    CircuitIsolationUnavailable,
+    /// Why the `--profile-dir` can not be a directory, see `utils::check_profile_dir`.
+    ProfileDir(&'static str),
+    InvalidPath { input: String, reason: String },
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::HelpRequested => write!(f, "help requested"),
+            ConfigError::VersionRequested => write!(f, "version requested"),
+            ConfigError::SetupRequested => write!(f, "setup requested"),
+            ConfigError::CompletionsRequested(shell) => write!(f, "{} completions requested", shell),
+
+            ConfigError::UnknownArg(arg) => write!(f, "Unknown argument: {}", arg),
+            ConfigError::MissingValue(flag) => write!(f, "{} requires a value", flag),
+            ConfigError::UnexpectedValue(flag) => write!(f, "{} does not take a value", flag),
+            ConfigError::ConflictingFlags(a, b) => write!(f, "{} and {} cannot be used together", a, b),
+            ConfigError::DuplicateFlag(flag) => write!(f, "{} specified more than once", flag),
+            ConfigError::RequiresFlag(flag, required) => write!(f, "{} requires {}", flag, required),
+            ConfigError::InvalidFormat(input) => write!(f, "Invalid output format: {} (allowed: human, json)", input),
+            ConfigError::InvalidShell(input) => write!(f, "Invalid shell: {} (allowed: bash, zsh, fish)", input),
+            ConfigError::InvalidProfile(input) => {
+                write!(f, "Invalid profile name: {} (use letters, digits, '-' and '_')", input)
+            }
+
+            ConfigError::InvalidEnvValue { name, value } => {
+                write!(f, "Invalid value for {}: {} (allowed: 1, 0, true, false, yes, no)", name, value)
+            }
+            ConfigError::ConfigFile(reason) => write!(f, "{}", reason),
+            ConfigError::InvalidConfigFile { path, reason } => write!(f, "Invalid config file {}: {}", path, reason),
+            ConfigError::ProfileNotFound { path, profile, available } => {
+                let available = if available.is_empty() { String::from("none defined") } else { available.join(", ") };
+                write!(f, "Invalid --profile for config file {}: profile `{}` not found (available: {})", path, profile, available)
+            }
+            ConfigError::ResponseFile(reason) => write!(f, "{}", reason),
+            ConfigError::ServerFile(reason) => write!(f, "{}", reason),
+
+            ConfigError::InvalidProxyType(input) => {
+                write!(f, "Invalid proxy type: {} (allowed: HTTP, SOCKS4, SOCKS5, SOCKS5H)", input)
+            }
+            ConfigError::InvalidProxyAddr { input, reason } => write!(f, "Invalid proxy address ({}): {}", input, reason),
+            ConfigError::InvalidProxyUrl { reason } => write!(f, "Invalid proxy URL: {}", reason),
+            ConfigError::ProxyPassword(reason) => write!(f, "{}", reason),
+            ConfigError::Socks4Password => {
+                write!(f, "SOCKS4 proxies have no password authentication, only a user ID (--proxy-user)")
+            }
+            ConfigError::HttpProxyCredentials(reason) => write!(f, "Unsupported HTTP proxy credentials: {}", reason),
+            ConfigError::StatePassword(reason) => write!(f, "{}", reason),
+
+            ConfigError::InvalidServerUrl { index, input, reason } => {
+                write!(f, "Invalid --server entry #{} ({}): {}", index, input, reason)
+            }
+            ConfigError::MalformedServerUrl { input, reason } => write!(f, "Invalid server URL ({}): {}", input, reason),
+            ConfigError::InvalidHost { input, reason } => write!(f, "Invalid host '{}': {}", input, reason),
+            ConfigError::InvalidPort { input, reason } => write!(f, "Invalid port ({}): {}", input, reason),
+            ConfigError::InvalidDefaultServerPort { input, reason } => {
+                write!(f, "Invalid --default-server-port ({}): {}", input, reason)
+            }
+            ConfigError::CaFile(reason) => write!(f, "{}", reason),
+            ConfigError::InvalidDohUrl(input) => {
+                write!(f, "Invalid --doh URL: {} (expected an https:// endpoint, e.g. https://9.9.9.9/dns-query)", input)
+            }
+            ConfigError::InvalidUserAgent(input) => {
+                write!(f, "Invalid --user-agent: {:?} (must not be empty or contain control characters)", input)
+            }
+            ConfigError::InvalidBindAddress(input) => {
+                write!(f, "Invalid --bind-address: {} (expected an IPv4 or IPv6 address, e.g. 10.8.0.2)", input)
+            }
+            ConfigError::InvalidMetricsAddr(input) => {
+                write!(f, "Invalid --metrics-addr: {} (expected an IP address and port, e.g. 127.0.0.1:9477)", input)
+            }
+            ConfigError::InvalidErrorFormat(input) => write!(f, "Invalid --error-format: {} (allowed: human, json)", input),
+            ConfigError::InvalidQueueMax(input) => {
+                write!(f, "Invalid --queue-max: {} (expected a whole number, 0 disables the queue)", input)
+            }
+            ConfigError::InvalidConnectRetries(input) => {
+                write!(f, "Invalid --connect-retries: {} (expected a whole number, 0 fails on the first error)", input)
+            }
+            ConfigError::InvalidSamples(input) => {
+                write!(f, "Invalid --samples: {} (expected a whole number from 1 to {})", input, consts::MAX_BENCH_SAMPLES)
+            }
+            ConfigError::InvalidMaxResponseBytes(input) => {
+                write!(f, "Invalid --max-response-bytes: {} (expected a whole number of bytes greater than 0)", input)
+            }
+            ConfigError::InvalidStateFormat(input) => write!(f, "Invalid --state-format: {} (expected json or binary)", input),
+            ConfigError::InvalidMinTls(input) => write!(f, "Invalid --min-tls: {} (expected 1.2 or 1.3)", input),
+            ConfigError::InvalidAutosave(input) => {
+                write!(f, "Invalid --autosave-secs: {} (expected a whole number of seconds, 0 saves on every change)", input)
+            }
+            ConfigError::InvalidReconnect(reason) => write!(f, "{}", reason),
+            ConfigError::InvalidPin(input) => {
+                write!(f, "Invalid --pin-sha256: {} (expected the base64 SHA-256 of the server's public key)", input)
+            }
+            ConfigError::StateFilePath(reason) => write!(f, "--state-file {}", reason),
+            ConfigError::ProfileDir(reason) => write!(f, "--profile-dir {}", reason),
+            ConfigError::CircuitIsolationUnavailable => {
+                write!(f, "--isolate-circuits failed to generate its secret, the system has no usable secure random source")
+            }
+            ConfigError::InvalidPath { input, reason } => write!(f, "Invalid path ({}): {}", input, reason),
+            ConfigError::InvalidTimeout(input) => {
+                write!(f, "Invalid timeout: {} (expected a whole number of seconds greater than 0)", input)
+            }
+        }
+    }
+}
+
+impl ConfigError {
+    /// `exit_code::USAGE` for every real error, `--help`, `--version` and the subcommands main handles exit successfully.
+    /// A system failure while building the config is `exit_code::FAILURE`, the options themselves were fine.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ConfigError::HelpRequested
+            | ConfigError::VersionRequested
+            | ConfigError::SetupRequested
+            | ConfigError::CompletionsRequested(_) => exit_code::SUCCESS,
+            ConfigError::CircuitIsolationUnavailable => exit_code::FAILURE,
+            _ => exit_code::USAGE,
+        }
+    }
+
+    /// The flag (or environment variable) the error is about, so a UI wrapping the binary can highlight
+    /// the right field.
+    pub fn argument(&self) -> Option<&str> {
+        match self {
+            ConfigError::UnknownArg(arg) => Some(arg),
+            ConfigError::MissingValue(flag)
+            | ConfigError::DuplicateFlag(flag)
+            | ConfigError::ConflictingFlags(flag, _)
+            | ConfigError::RequiresFlag(flag, _) => Some(flag),
+            ConfigError::UnexpectedValue(flag) => Some(flag),
+            ConfigError::InvalidFormat(_) => Some("--format"),
+            ConfigError::InvalidProfile(_) => Some("--profile"),
+            ConfigError::InvalidEnvValue { name, .. } => Some(name),
+            ConfigError::ConfigFile(_) | ConfigError::InvalidConfigFile { .. } => Some("--config"),
+            ConfigError::ProfileNotFound { .. } => Some("--profile"),
+            ConfigError::ServerFile(_) => Some("--server-file"),
+            ConfigError::InvalidProxyType(_) => Some("--proxy-type"),
+            ConfigError::InvalidProxyAddr { .. } => Some("--proxy-addr"),
+            ConfigError::InvalidProxyUrl { .. } => Some("--proxy"),
+            ConfigError::ProxyPassword(_) | ConfigError::Socks4Password => Some("--proxy-pass"),
+            ConfigError::StatePassword(_) => Some("--state-pass-file"),
+            ConfigError::InvalidServerUrl { .. } | ConfigError::MalformedServerUrl { .. } => Some("--server"),
+            ConfigError::InvalidDefaultServerPort { .. } => Some("--default-server-port"),
+            ConfigError::InvalidTimeout(_) => Some("--timeout"),
+            ConfigError::InvalidPin(_) => Some("--pin-sha256"),
+            ConfigError::CaFile(_) => Some("--ca-file"),
+            ConfigError::InvalidDohUrl(_) => Some("--doh"),
+            ConfigError::InvalidUserAgent(_) => Some("--user-agent"),
+            ConfigError::InvalidQueueMax(_) => Some("--queue-max"),
+            ConfigError::InvalidConnectRetries(_) => Some("--connect-retries"),
+            ConfigError::InvalidSamples(_) => Some("--samples"),
+            ConfigError::InvalidAutosave(_) => Some("--autosave-secs"),
+            ConfigError::InvalidStateFormat(_) => Some("--state-format"),
+            ConfigError::InvalidMinTls(_) => Some("--min-tls"),
+            ConfigError::InvalidMaxResponseBytes(_) => Some("--max-response-bytes"),
+            ConfigError::InvalidBindAddress(_) => Some("--bind-address"),
+            ConfigError::InvalidMetricsAddr(_) => Some("--metrics-addr"),
+            ConfigError::InvalidErrorFormat(_) => Some("--error-format"),
+            ConfigError::StateFilePath(_) => Some("--state-file"),
+            ConfigError::ProfileDir(_) => Some("--profile-dir"),
+            ConfigError::CircuitIsolationUnavailable => Some("--isolate-circuits"),
+            _ => None,
+        }
+    }
+
+    /// The rejected value. Never set for anything that may hold a password, like a proxy URL.
+    pub fn input(&self) -> Option<&str> {
+        match self {
+            ConfigError::UnknownArg(input)
+            | ConfigError::InvalidFormat(input)
+            | ConfigError::InvalidShell(input)
+            | ConfigError::InvalidProfile(input)
+            | ConfigError::ProfileNotFound { profile: input, .. }
+            | ConfigError::InvalidProxyType(input)
+            | ConfigError::InvalidProxyAddr { input, .. }
+            | ConfigError::InvalidServerUrl { input, .. }
+            | ConfigError::MalformedServerUrl { input, .. }
+            | ConfigError::InvalidHost { input, .. }
+            | ConfigError::InvalidPort { input, .. }
+            | ConfigError::InvalidDefaultServerPort { input, .. }
+            | ConfigError::InvalidTimeout(input)
+            | ConfigError::InvalidPin(input)
+            | ConfigError::InvalidDohUrl(input)
+            | ConfigError::InvalidUserAgent(input)
+            | ConfigError::InvalidQueueMax(input)
+            | ConfigError::InvalidConnectRetries(input)
+            | ConfigError::InvalidSamples(input)
+            | ConfigError::InvalidAutosave(input)
+            | ConfigError::InvalidStateFormat(input)
+            | ConfigError::InvalidMinTls(input)
+            | ConfigError::InvalidMaxResponseBytes(input)
+            | ConfigError::InvalidBindAddress(input)
+            | ConfigError::InvalidMetricsAddr(input)
+            | ConfigError::InvalidErrorFormat(input)
+            | ConfigError::InvalidPath { input, .. } => Some(input),
+            ConfigError::InvalidEnvValue { value, .. } => Some(value),
+            _ => None,
+        }
+    }
+
+    /// `--error-format json`: the variant name, the message printed in human mode and, when known,
+    /// `input` and `argument`, e.g.
+    /// `{"error":"InvalidProxyAddr","message":"...","input":"127.0.0.1:x","argument":"--proxy-addr"}`.
+    pub fn to_json(&self) -> String {
+        error_json(&variant_name(self), &self.to_string(), self.input(), self.argument())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+
+/// Why a `--check` connectivity test failed, each variant is a one-line diagnosis.
+#[derive(Debug)]
+pub enum CheckError {
+    NoServer,
+    Dns(String),
+    ConnectionRefused(String),
+    Timeout(String),
+    ProxyAuth(String),
+    Unreachable(String),
+    Proxy(String),
+    Other(String),
+    /// More than `consts::BENCH_MAX_FAILED_FRACTION` of the `bench-proxy` connections failed.
+    TooManyFailures { failed: u32, samples: u32 },
+}
+
+impl std::fmt::Display for CheckError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CheckError::NoServer => write!(f, "no server to check, pass --server <url>"),
+            CheckError::Dns(detail) => write!(f, "DNS lookup failed: {}", detail),
+            CheckError::ConnectionRefused(detail) => write!(f, "connection refused: {}", detail),
+            CheckError::Timeout(detail) => write!(f, "timed out: {}", detail),
+            CheckError::ProxyAuth(detail) => write!(f, "proxy authentication failed: {}", detail),
+            CheckError::Unreachable(detail) => write!(f, "server unreachable: {}", detail),
+            CheckError::Proxy(detail) => write!(f, "proxy error: {}", detail),
+            CheckError::Other(detail) => write!(f, "{}", detail),
+            CheckError::TooManyFailures { failed, samples } => write!(f, "{} of {} connections failed", failed, samples),
+        }
+    }
+}
+
+impl CheckError {
+    /// `exit_code::USAGE` when there is nothing to check, `exit_code::CONNECTION` otherwise.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            CheckError::NoServer => exit_code::USAGE,
+            _ => exit_code::CONNECTION,
+        }
+    }
+}
+
+impl CheckError {
+    /// `--error-format json` for a failed `--check`.
+    pub fn to_json(&self) -> String {
+        error_json(&variant_name(self), &self.to_string(), None, None)
+    }
+}
+
+impl std::error::Error for CheckError {}
+
+
+/// The name of an enum variant, the start of its derived `Debug` output.
+fn variant_name(value: &impl std::fmt::Debug) -> String {
+    let mut debug = format!("{:?}", value);
+    let end = debug.find(|c: char| !c.is_ascii_alphanumeric()).unwrap_or(debug.len());
+    debug.truncate(end);
+
+    debug
+}
+
+
+fn error_json(name: &str, message: &str, input: Option<&str>, argument: Option<&str>) -> String {
+    let mut object = Map::new();
+    object.insert(String::from("error"), Value::from(name));
+    object.insert(String::from("message"), Value::from(message));
+    if let Some(input) = input {
+        object.insert(String::from("input"), Value::from(input));
+    }
+    if let Some(argument) = argument {
+        object.insert(String::from("argument"), Value::from(argument));
+    }
+
+    Value::Object(object).to_string()
+}
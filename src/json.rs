@@ -1,3 +1,23 @@
+use std::time::Duration;
+
+use serde::Serializer;
+
+
+/// `serialize_with` helper that writes `"***"` in place of a secret, or `null` when it is unset.
+pub fn serialize_redacted<T, S: Serializer>(value: &Option<T>, serializer: S) -> Result<S::Ok, S::Error> {
+    match value {
+        Some(_) => serializer.serialize_some("***"),
+        None => serializer.serialize_none(),
+    }
+}
+
+
+/// `serialize_with` helper that writes a duration as whole seconds, the unit every timeout option uses.
+pub fn serialize_secs<S: Serializer>(value: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u64(value.as_secs())
+}
+
+
 pub fn kv_pairs_to_json(metadata: &[(String, String)]) -> String {
     let mut json = String::from("{");
     for (i, (k, v)) in metadata.iter().enumerate() {
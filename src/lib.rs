@@ -0,0 +1,34 @@
+mod error;
+mod utils;
+mod json;
+mod consts;
+mod crypto;
+mod transport;
+mod tls;
+mod bind;
+mod direct;
+mod socks;
+mod requests;
+mod doh;
+mod config;
+mod state;
+mod contacts;
+mod client;
+mod cli;
+mod check;
+mod setup;
+mod completions;
+mod shutdown;
+mod metrics;
+mod trace;
+
+pub use error::{CheckError, ConfigError, Error, exit_code};
+pub use requests::{ProxyInfo, ProxySource, ProxyType, TlsVersion};
+pub use client::{Config, prompt_user, sanitize_message};
+pub use state::StateFormat;
+pub use contacts::{Conflict, ContactEntry, Merge};
+pub use cli::{ConfigBuilder, OutputFormat, ServerUrl, clean_server_url, error_format_from, parse_args, parse_args_from, parse_proxy_addr, usage};
+pub use check::{BenchReport, bench_connectivity, check_connectivity, check_description, resolved_addresses};
+pub use setup::run_setup;
+pub use completions::{Shell, completion_script};
+pub use shutdown::{install_handlers as install_shutdown_handlers, requested as shutdown_requested};
@@ -0,0 +1,159 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use crate::error::Error;
+
+
+/// True while the last request reached the server, false before the first one.
+static CONNECTED: AtomicBool = AtomicBool::new(false);
+
+/// Reconnect attempts since startup, see `Config::with_reconnect`.
+static RECONNECTS: AtomicU64 = AtomicU64::new(0);
+
+/// Messages waiting in the outbound queue.
+static QUEUE_DEPTH: AtomicU64 = AtomicU64::new(0);
+
+/// Unix time of the last longpoll the server answered, 0 before the first one.
+static LAST_SYNC: AtomicU64 = AtomicU64::new(0);
+
+/// Longest request line or header we read, anything else is not a scraper.
+const MAX_LINE: u64 = 8192;
+
+
+/// Record whether a request reached the server.
+pub(crate) fn record_connection(connected: bool) {
+    CONNECTED.store(connected, Ordering::Relaxed);
+}
+
+pub(crate) fn record_reconnect() {
+    RECONNECTS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub(crate) fn record_queue_depth(depth: usize) {
+    QUEUE_DEPTH.store(depth as u64, Ordering::Relaxed);
+}
+
+pub(crate) fn record_sync(unix_secs: u64) {
+    LAST_SYNC.store(unix_secs, Ordering::Relaxed);
+}
+
+
+/// The current values in the Prometheus text exposition format.
+pub(crate) fn render() -> String {
+    let mut out = String::new();
+
+    metric(&mut out, "coldwire_connected", "gauge", "1 if the last request reached the server, 0 otherwise", CONNECTED.load(Ordering::Relaxed) as u64);
+    metric(&mut out, "coldwire_reconnects_total", "counter", "Reconnect attempts since startup", RECONNECTS.load(Ordering::Relaxed));
+    metric(&mut out, "coldwire_queue_depth", "gauge", "Outgoing messages waiting for the relay", QUEUE_DEPTH.load(Ordering::Relaxed));
+    metric(&mut out, "coldwire_last_sync_timestamp_seconds", "gauge", "Unix time of the last successful sync, 0 if there was none", LAST_SYNC.load(Ordering::Relaxed));
+
+    out
+}
+
+fn metric(out: &mut String, name: &str, kind: &str, help: &str, value: u64) {
+    out.push_str(&format!("# HELP {} {}\n# TYPE {} {}\n{} {}\n", name, help, name, kind, name, value));
+}
+
+
+/// Listen on `addr` and answer scrapes from a background thread for the rest of the process, returns
+/// the bound address (the port is picked by the system for port 0).
+///
+/// `GET /metrics` returns `render`, `GET /health` is 200 while connected and 503 otherwise so a plain
+/// HTTP check can alert on it. Clients are served one at a time, a slow one only delays the next scrape.
+pub(crate) fn serve(addr: SocketAddr) -> Result<SocketAddr, Error> {
+    let listener = TcpListener::bind(addr).map_err(|_| Error::FailedToStartMetricsServer)?;
+    let local_addr = listener.local_addr().map_err(|_| Error::FailedToStartMetricsServer)?;
+
+    thread::Builder::new()
+        .name(String::from("metrics"))
+        .spawn(move || {
+            for stream in listener.incoming().flatten() {
+                // a scraper that hung up is its own problem
+                let _ = respond(stream);
+            }
+        })
+        .map_err(|_| Error::FailedToStartMetricsServer)?;
+
+    Ok(local_addr)
+}
+
+fn respond(mut stream: TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(5)))?;
+
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    (&mut reader).take(MAX_LINE).read_line(&mut request_line)?;
+
+    // read the headers too, closing with unread data makes the kernel reset the connection
+    loop {
+        let mut header = String::new();
+        if (&mut reader).take(MAX_LINE).read_line(&mut header)? == 0 || header.trim_end().is_empty() {
+            break;
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", render()),
+        (Some("GET"), Some("/health")) if CONNECTED.load(Ordering::Relaxed) => ("200 OK", String::from("ok\n")),
+        (Some("GET"), Some("/health")) => ("503 Service Unavailable", String::from("disconnected\n")),
+        (Some("GET"), _) => ("404 Not Found", String::from("not found\n")),
+        _ => ("405 Method Not Allowed", String::from("method not allowed\n")),
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    fn get(addr: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_render_format() {
+        let out = render();
+
+        for name in ["coldwire_connected", "coldwire_reconnects_total", "coldwire_queue_depth", "coldwire_last_sync_timestamp_seconds"] {
+            assert!(out.contains(&format!("# TYPE {} ", name)), "{}", out);
+            assert!(out.lines().any(|l| l.starts_with(&format!("{} ", name))), "{}", out);
+        }
+        assert!(out.ends_with('\n'));
+    }
+
+    #[test]
+    fn test_serve() {
+        let addr = serve(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).unwrap();
+        record_queue_depth(3);
+
+        let response = get(addr, "/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.contains("\ncoldwire_queue_depth 3\n"), "{}", response);
+
+        let response = get(addr, "/health");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n") || response.starts_with("HTTP/1.1 503 "), "{}", response);
+
+        let response = get(addr, "/other");
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"), "{}", response);
+    }
+}
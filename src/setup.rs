@@ -0,0 +1,351 @@
+use std::fmt;
+use std::path::Path;
+
+use zeroize::Zeroizing;
+
+use crate::cli::{self, ServerUrl, clean_server_url, parse_proxy_addr};
+use crate::client::{prompt_secret, prompt_user};
+use crate::error::Error;
+use crate::requests::ProxyType;
+use crate::utils;
+
+
+/// Everything `coldwire-desktop setup` asks for.
+struct SetupAnswers {
+    server_url: ServerUrl,
+    proxy: Option<ProxyAnswers>,
+    state_file_path: String,
+}
+
+struct ProxyAnswers {
+    proxy_type: ProxyType,
+    addr: String,
+    user: Option<Zeroizing<String>>,
+    pass: Option<Zeroizing<String>>,
+}
+
+
+/// First-run wizard: ask for the server, Tor or another proxy and the state file, write them to a
+/// config file (0600 on Unix) and print the matching command line.
+///
+/// Every answer goes through the same checks as the flags it stands for, an invalid one is asked again.
+pub fn run_setup() -> Result<(), Error> {
+    println!("[*] Coldwire setup, press Enter to keep the value in [brackets].\n");
+
+    let server_url = prompt_until("Server URL: ", None, |s| clean_server_url(s.to_string(), true))?;
+    if server_url.is_plaintext() {
+        println!("[!] {} is plaintext http, anyone on the network path can read and tamper with your traffic.", server_url);
+    }
+    let is_onion = server_url.host.to_ascii_lowercase().ends_with(".onion");
+
+    let proxy = if prompt_yes_no("Connect through Tor?", true)? {
+        let default_addr = cli::detect_tor_proxy_addr(false);
+        let addr = prompt_until(
+            &format!("Tor SOCKS address [{}]: ", default_addr),
+            Some(&default_addr),
+            |s| parse_proxy_addr(s).map(|_| s.to_string()),
+        )?;
+
+        Some(ProxyAnswers { proxy_type: ProxyType::Socks5h, addr, user: None, pass: None })
+    } else if prompt_yes_no("Connect through another proxy?", false)? {
+        Some(prompt_proxy()?)
+    } else {
+        None
+    };
+
+    if is_onion && proxy.as_ref().is_none_or(|p| p.proxy_type != ProxyType::Socks5h) {
+        println!("[!] {} is an onion service, it can only be reached through Tor (SOCKS5H).", server_url.host);
+    }
+
+    let default_state = utils::default_state_file_path();
+    let state_file_path = prompt_until(
+        &bracketed("State file path", default_state.as_deref()),
+        default_state.as_deref(),
+        checked_file_path,
+    )?;
+
+    let answers = SetupAnswers { server_url, proxy, state_file_path };
+
+    let default_config = utils::default_config_file_path();
+    let config_path = loop {
+        let path = prompt_until(
+            &bracketed("Write the config file to", default_config.as_deref()),
+            default_config.as_deref(),
+            checked_file_path,
+        )?;
+
+        if !Path::new(&path).exists() || prompt_yes_no(&format!("{} exists, overwrite it?", path), false)? {
+            break path;
+        }
+    };
+
+    utils::create_parent_dirs(&config_path)?;
+    utils::write_state_atomic(&config_path, render_config(&answers).as_bytes())?;
+
+    println!("\n[*] Wrote {}", config_path);
+    println!("[*] Start Coldwire with:\n");
+    println!("    coldwire-desktop --config {}\n", shell_quote(&config_path));
+    println!("[*] or, without the config file:\n");
+    println!("    {}\n", equivalent_command(&answers));
+    if answers.proxy.as_ref().is_some_and(|p| p.pass.is_some()) {
+        println!("[*] Add --proxy-pass-file <path> to that command for the proxy password, it is only stored in the config file.");
+    }
+
+    Ok(())
+}
+
+
+fn prompt_proxy() -> Result<ProxyAnswers, Error> {
+    let proxy_type = prompt_until(
+        "Proxy type (HTTP, SOCKS4, SOCKS5, SOCKS5H) [SOCKS5]: ",
+        Some("SOCKS5"),
+        |s| s.parse::<ProxyType>().map_err(|e| e.to_string()),
+    )?;
+    let addr = prompt_until("Proxy address (host:port): ", None, |s| parse_proxy_addr(s).map(|_| s.to_string()))?;
+
+    let user = prompt_user("Proxy username (leave empty for none): ", true)?;
+    if user.is_empty() {
+        return Ok(ProxyAnswers { proxy_type, addr, user: None, pass: None });
+    }
+
+    // SOCKS4 has a user ID but no password
+    let pass = if proxy_type == ProxyType::Socks4 {
+        None
+    } else {
+        Some(prompt_secret("Proxy password (not shown, leave empty for none): ")?).filter(|p| !p.is_empty())
+    };
+
+    Ok(ProxyAnswers { proxy_type, addr, user: Some(user), pass })
+}
+
+
+/// Ask until `check` accepts the answer, an empty answer takes `default` when there is one.
+fn prompt_until<T, E, F>(msg: &str, default: Option<&str>, check: F) -> Result<T, Error>
+where
+    E: fmt::Display,
+    F: Fn(&str) -> Result<T, E>,
+{
+    loop {
+        let input = prompt_user(msg, true)?;
+        let answer = match (input.is_empty(), default) {
+            (false, _) => input.as_str(),
+            (true, Some(default)) => default,
+            (true, None) => {
+                println!("Please enter a value!\n");
+                continue;
+            }
+        };
+
+        match check(answer) {
+            Ok(value) => return Ok(value),
+            Err(e) => println!("Invalid value: {}\n", e),
+        }
+    }
+}
+
+
+fn prompt_yes_no(question: &str, default: bool) -> Result<bool, Error> {
+    let hint = if default { "[Y/n]" } else { "[y/N]" };
+
+    loop {
+        let input = prompt_user(&format!("{} {}: ", question, hint), true)?;
+        match input.to_ascii_lowercase().as_str() {
+            "" => return Ok(default),
+            "y" | "yes" => return Ok(true),
+            "n" | "no" => return Ok(false),
+            _ => println!("Please answer y or n!\n"),
+        }
+    }
+}
+
+
+fn bracketed(label: &str, default: Option<&str>) -> String {
+    match default {
+        Some(default) => format!("{} [{}]: ", label, default),
+        None => format!("{}: ", label),
+    }
+}
+
+
+/// Expand `~` and `$VAR` like `--state-file` does and make sure the result can be a file.
+fn checked_file_path(path: &str) -> Result<String, String> {
+    let path = utils::expand_path(path)?;
+    utils::check_state_file_path(&path).map_err(|reason| format!("that path {}", reason))?;
+
+    Ok(path)
+}
+
+
+/// The answers as a config file `load_config_file` reads back.
+fn render_config(answers: &SetupAnswers) -> Zeroizing<String> {
+    let mut out = Zeroizing::new(String::from("# Written by `coldwire-desktop setup`.\n"));
+
+    out.push_str(&format!("server_url = {}\n", toml_string(&answers.server_url.to_string()).as_str()));
+    out.push_str(&format!("state_file_path = {}\n", toml_string(&answers.state_file_path).as_str()));
+    out.push_str("\n[proxy]\n");
+
+    // an explicit `enabled = false` so a proxy from the environment is not picked up either
+    let Some(proxy) = &answers.proxy else {
+        out.push_str("enabled = false\n");
+        return out;
+    };
+
+    out.push_str(&format!("type = {}\n", toml_string(&proxy.proxy_type.to_string()).as_str()));
+    out.push_str(&format!("addr = {}\n", toml_string(&proxy.addr).as_str()));
+    if let Some(user) = &proxy.user {
+        out.push_str(&format!("user = {}\n", toml_string(user).as_str()));
+    }
+    if let Some(pass) = &proxy.pass {
+        out.push_str(&Zeroizing::new(format!("pass = {}\n", toml_string(pass).as_str())));
+    }
+
+    out
+}
+
+
+/// A basic TOML string, control characters other than those with a short escape as `\uXXXX`.
+fn toml_string(s: &str) -> Zeroizing<String> {
+    let mut out = Zeroizing::new(String::with_capacity(s.len() + 2));
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04X}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+
+    out
+}
+
+
+/// The command line that does the same as the written config file, minus the proxy password.
+fn equivalent_command(answers: &SetupAnswers) -> String {
+    let mut args = vec![
+        String::from("coldwire-desktop"),
+        String::from("--server"),
+        shell_quote(&answers.server_url.to_string()),
+    ];
+
+    match &answers.proxy {
+        Some(proxy) => {
+            args.push(String::from("--use-proxy"));
+            args.push(String::from("--proxy-type"));
+            args.push(proxy.proxy_type.to_string());
+            args.push(String::from("--proxy-addr"));
+            args.push(shell_quote(&proxy.addr));
+            if let Some(user) = &proxy.user {
+                args.push(String::from("--proxy-user"));
+                args.push(shell_quote(user));
+            }
+        }
+        None => args.push(String::from("--no-proxy")),
+    }
+
+    args.push(String::from("--state-file"));
+    args.push(shell_quote(&answers.state_file_path));
+
+    args.join(" ")
+}
+
+
+/// Single-quote `s` for a POSIX shell unless it is made of characters that never need quoting.
+fn shell_quote(s: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "/._-:@%+=,".contains(c);
+
+    if !s.is_empty() && s.chars().all(safe) {
+        return s.to_string();
+    }
+
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::parse_config;
+
+    fn answers(proxy: Option<ProxyAnswers>) -> SetupAnswers {
+        SetupAnswers {
+            server_url: clean_server_url(String::from("relay.example.org"), true).unwrap(),
+            proxy,
+            state_file_path: String::from("/home/user/coldwire state"),
+        }
+    }
+
+    #[test]
+    fn test_render_config_round_trip() {
+        let proxy = ProxyAnswers {
+            proxy_type: ProxyType::Socks5,
+            addr: String::from("[::1]:1080"),
+            user: Some(Zeroizing::new(String::from("alice"))),
+            pass: Some(Zeroizing::new(String::from(" p\"a\\ss\t\u{7f}"))),
+        };
+
+        let parsed = parse_config(&render_config(&answers(Some(proxy)))).unwrap();
+
+        assert_eq!(parsed.server_url.as_deref().map(String::as_str), Some("https://relay.example.org/"));
+        assert_eq!(parsed.state_file_path.as_deref().map(String::as_str), Some("/home/user/coldwire state"));
+        assert_eq!(parsed.use_proxy, Some(true));
+        assert_eq!(parsed.proxy_type.as_deref(), Some("SOCKS5"));
+        assert_eq!(parsed.proxy_addr.as_deref().map(String::as_str), Some("[::1]:1080"));
+        assert_eq!(parsed.proxy_user.as_deref().map(String::as_str), Some("alice"));
+        assert_eq!(parsed.proxy_pass.as_deref().map(String::as_str), Some(" p\"a\\ss\t\u{7f}"));
+    }
+
+    #[test]
+    fn test_render_config_without_proxy_disables_it() {
+        let parsed = parse_config(&render_config(&answers(None))).unwrap();
+
+        assert_eq!(parsed.use_proxy, Some(false));
+        assert!(parsed.proxy_addr.is_none());
+    }
+
+    #[test]
+    fn test_equivalent_command() {
+        let tor = ProxyAnswers {
+            proxy_type: ProxyType::Socks5h,
+            addr: String::from("127.0.0.1:9150"),
+            user: None,
+            pass: None,
+        };
+
+        assert_eq!(
+            equivalent_command(&answers(Some(tor))),
+            "coldwire-desktop --server https://relay.example.org/ --use-proxy --proxy-type SOCKS5H \
+             --proxy-addr 127.0.0.1:9150 --state-file '/home/user/coldwire state'"
+        );
+        assert_eq!(
+            equivalent_command(&answers(None)),
+            "coldwire-desktop --server https://relay.example.org/ --no-proxy --state-file '/home/user/coldwire state'"
+        );
+    }
+
+    #[test]
+    fn test_equivalent_command_never_contains_the_password() {
+        let proxy = ProxyAnswers {
+            proxy_type: ProxyType::Http,
+            addr: String::from("proxy.lan:3128"),
+            user: Some(Zeroizing::new(String::from("bob"))),
+            pass: Some(Zeroizing::new(String::from("hunter2"))),
+        };
+
+        let command = equivalent_command(&answers(Some(proxy)));
+        assert!(command.contains("--proxy-user bob"));
+        assert!(!command.contains("hunter2"));
+    }
+
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!(shell_quote("/home/user/state"), "/home/user/state");
+        assert_eq!(shell_quote("a b"), "'a b'");
+        assert_eq!(shell_quote("it's"), "'it'\\''s'");
+        assert_eq!(shell_quote("$HOME"), "'$HOME'");
+        assert_eq!(shell_quote(""), "''");
+    }
+}
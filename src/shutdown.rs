@@ -0,0 +1,87 @@
+use std::io::IsTerminal;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::error::Error;
+use crate::utils;
+
+
+/// Set by the first SIGINT / SIGTERM (Ctrl-C / console close on Windows).
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// True while the main thread is blocked waiting for user input.
+static IDLE: AtomicBool = AtomicBool::new(false);
+
+/// True while terminal echo is off for a secret prompt, an exit must turn it back on.
+static ECHO_OFF: AtomicBool = AtomicBool::new(false);
+
+
+/// Install the SIGINT / SIGTERM handler, call once at startup.
+///
+/// A signal that arrives while we are waiting for input exits right away, the state file is
+/// written atomically after every change so there is nothing left to flush. A signal that arrives
+/// in the middle of a request or a state file write only sets a flag, which the main loop polls
+/// through `requested` to save and exit once the operation is done. Further signals are ignored.
+pub fn install_handlers() -> Result<(), Error> {
+    ctrlc::set_handler(|| {
+        if REQUESTED.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        if IDLE.load(Ordering::SeqCst) {
+            restore_echo();
+            println!("\n[*] Shutting down.");
+            std::process::exit(0);
+        }
+
+        eprintln!("\n[*] Shutting down after the current operation finishes...");
+    })
+    .map_err(|_| Error::FailedToInstallSignalHandler)
+}
+
+
+/// True once a shutdown signal was received.
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}
+
+
+/// Run `f` (a blocking read of user input) with shutdown signals allowed to exit immediately.
+pub fn while_idle<T, F: FnOnce() -> T>(f: F) -> T {
+    IDLE.store(true, Ordering::SeqCst);
+
+    // the signal came in while we were busy, do not make the user answer one more prompt first
+    if requested() {
+        restore_echo();
+        println!("\n[*] Shutting down.");
+        std::process::exit(0);
+    }
+
+    let result = f();
+    IDLE.store(false, Ordering::SeqCst);
+
+    result
+}
+
+
+/// Run `f` (a prompt for a secret) with terminal echo turned off when stdin is a terminal.
+pub fn without_echo<T, F: FnOnce() -> T>(f: F) -> T {
+    let echo_off = std::io::stdin().is_terminal() && utils::set_terminal_echo(false);
+    ECHO_OFF.store(echo_off, Ordering::SeqCst);
+
+    let result = f();
+
+    if echo_off {
+        restore_echo();
+        // the newline typed by the user was not echoed either
+        println!();
+    }
+
+    result
+}
+
+
+fn restore_echo() {
+    if ECHO_OFF.swap(false, Ordering::SeqCst) {
+        utils::set_terminal_echo(true);
+    }
+}
@@ -0,0 +1,374 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs};
+
+use ureq::unversioned::transport::{ConnectionDetails, Connector};
+
+use crate::requests::ProxyInfo;
+use crate::transport::StreamTransport;
+
+
+/// Where a SOCKS5 proxy should connect to.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Socks5Target {
+    /// Resolved locally (plain SOCKS5, or an IP literal).
+    Addr(SocketAddr),
+    /// Resolved by the proxy (SOCKS5H).
+    Domain(String, u16),
+}
+
+
+impl fmt::Display for Socks5Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Socks5Target::Addr(addr) => write!(f, "{}", addr),
+            Socks5Target::Domain(host, port) => write!(f, "{}:{}", host, port),
+        }
+    }
+}
+
+
+/// Why a SOCKS5 handshake failed.
+#[derive(Debug)]
+pub(crate) enum Socks5Error {
+    Io(io::Error),
+    /// The proxy accepted none of the offered methods, or wants credentials we don't have.
+    NoAcceptableMethod,
+    /// The RFC 1929 status was not 0, the username or password is wrong.
+    AuthRejected,
+    CredentialsTooLong,
+    HostnameTooLong,
+    /// The CONNECT request failed with this reply code.
+    ConnectFailed(u8),
+    Protocol(String),
+}
+
+impl fmt::Display for Socks5Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Socks5Error::Io(e) => write!(f, "{}", e),
+            Socks5Error::NoAcceptableMethod => write!(f, "SOCKS5 proxy requires authentication we did not offer"),
+            Socks5Error::AuthRejected => write!(f, "SOCKS5 proxy rejected the username or password"),
+            Socks5Error::CredentialsTooLong => write!(f, "SOCKS5 username and password must be at most 255 bytes"),
+            Socks5Error::HostnameTooLong => write!(f, "hostname too long for SOCKS5"),
+            Socks5Error::ConnectFailed(code) => write!(f, "SOCKS5 proxy failed with reply code 0x{:02x}", code),
+            Socks5Error::Protocol(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl std::error::Error for Socks5Error {}
+
+impl From<io::Error> for Socks5Error {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::UnexpectedEof => Socks5Error::Protocol(String::from("proxy closed the connection during the handshake")),
+            _ => Socks5Error::Io(e),
+        }
+    }
+}
+
+
+/// Run the SOCKS5 greeting, the RFC 1929 username/password subnegotiation when `credentials` are given,
+/// and the CONNECT request on a stream already connected to the proxy.
+///
+/// Without credentials only "no auth" is offered. With them "no auth" and username/password are, and the
+/// proxy picks. On success the stream is positioned at the tunnelled data.
+pub(crate) fn socks5_handshake(stream: &mut TcpStream, credentials: Option<(&str, &str)>, target: &Socks5Target) -> Result<(), Socks5Error> {
+    if credentials.is_some() {
+        stream.write_all(&[0x05, 0x02, 0x00, 0x02])?;
+    } else {
+        stream.write_all(&[0x05, 0x01, 0x00])?;
+    }
+
+    let mut choice = [0u8; 2];
+    stream.read_exact(&mut choice)?;
+    if choice[0] != 0x05 {
+        return Err(Socks5Error::Protocol(String::from("proxy did not answer with SOCKS5")));
+    }
+
+    match (choice[1], credentials) {
+        (0x00, _) => {}
+        (0x02, Some((user, pass))) => {
+            if user.len() > 255 || pass.len() > 255 {
+                return Err(Socks5Error::CredentialsTooLong);
+            }
+
+            let mut auth = vec![0x01, user.len() as u8];
+            auth.extend_from_slice(user.as_bytes());
+            auth.push(pass.len() as u8);
+            auth.extend_from_slice(pass.as_bytes());
+            stream.write_all(&auth)?;
+
+            let mut status = [0u8; 2];
+            stream.read_exact(&mut status)?;
+            if status[1] != 0x00 {
+                return Err(Socks5Error::AuthRejected);
+            }
+        }
+        (0xFF, _) | (0x02, None) => return Err(Socks5Error::NoAcceptableMethod),
+        (other, _) => {
+            return Err(Socks5Error::Protocol(format!("SOCKS5 proxy chose unsupported auth method 0x{:02x}", other)));
+        }
+    }
+
+    let mut request = vec![0x05, 0x01, 0x00];
+    let port = match target {
+        Socks5Target::Addr(addr) => {
+            match addr.ip() {
+                IpAddr::V4(ip) => {
+                    request.push(0x01);
+                    request.extend_from_slice(&ip.octets());
+                }
+                IpAddr::V6(ip) => {
+                    request.push(0x04);
+                    request.extend_from_slice(&ip.octets());
+                }
+            }
+            addr.port()
+        }
+        Socks5Target::Domain(host, port) => {
+            if host.len() > 255 {
+                return Err(Socks5Error::HostnameTooLong);
+            }
+            request.push(0x03);
+            request.push(host.len() as u8);
+            request.extend_from_slice(host.as_bytes());
+            *port
+        }
+    };
+    request.extend_from_slice(&port.to_be_bytes());
+
+    stream.write_all(&request)?;
+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply)?;
+    if reply[1] != 0x00 {
+        return Err(Socks5Error::ConnectFailed(reply[1]));
+    }
+
+    // drain the bound address so the stream is positioned at tunnelled data
+    let addr_len = match reply[3] {
+        0x01 => 4,
+        0x04 => 16,
+        0x03 => {
+            let mut len = [0u8; 1];
+            stream.read_exact(&mut len)?;
+            len[0] as usize
+        }
+        other => return Err(Socks5Error::Protocol(format!("unexpected SOCKS5 address type 0x{:02x}", other))),
+    };
+    let mut bound = vec![0u8; addr_len + 2];
+    stream.read_exact(&mut bound)?;
+
+    Ok(())
+}
+
+
+/// Connector for SOCKS5 and SOCKS5H proxies, in place of ureq's `SocksConnector` so the handshake (and its
+/// errors) are the same as `--check`'s. TLS is chained on top of it like in the default chain.
+///
+/// The agent still has the proxy configured, so ureq resolves the target locally for SOCKS5 and hands us
+/// the addresses, and leaves it to the proxy for SOCKS5H.
+#[derive(Debug)]
+pub(crate) struct Socks5Connector {
+    proxy: ProxyInfo,
+}
+
+impl Socks5Connector {
+    pub(crate) fn new(proxy: ProxyInfo) -> Self {
+        Socks5Connector { proxy }
+    }
+}
+
+impl Connector<()> for Socks5Connector {
+    type Out = StreamTransport;
+
+    fn connect(&self, details: &ConnectionDetails, _chained: Option<()>) -> Result<Option<Self::Out>, ureq::Error> {
+        let target = match details.addrs.first() {
+            Some(addr) => Socks5Target::Addr(*addr),
+            None => {
+                let host = details.uri.host().ok_or(ureq::Error::HostNotFound)?;
+                let port = details.uri.port_u16().unwrap_or(if details.uri.scheme_str() == Some("https") { 443 } else { 80 });
+                Socks5Target::Domain(host.trim_start_matches('[').trim_end_matches(']').to_string(), port)
+            }
+        };
+
+        let timeout = details.timeout.not_zero().map(|t| *t);
+
+        let connect_span = tracing::debug_span!("connect", proxy = %format_args!("{}:{}", self.proxy.host, self.proxy.port)).entered();
+        let proxy_addrs: Vec<SocketAddr> = (self.proxy.host.as_str(), self.proxy.port).to_socket_addrs()?.collect();
+        let mut last_error = None;
+        let mut stream = None;
+        for addr in proxy_addrs {
+            let result = match timeout {
+                Some(timeout) => TcpStream::connect_timeout(&addr, timeout),
+                None => TcpStream::connect(addr),
+            };
+            match result {
+                Ok(s) => {
+                    stream = Some(s);
+                    break;
+                }
+                Err(e) => last_error = Some(e),
+            }
+        }
+        let mut stream = match stream {
+            Some(s) => s,
+            None => return Err(last_error.unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, "proxy has no address")).into()),
+        };
+
+        stream.set_read_timeout(timeout)?;
+        stream.set_write_timeout(timeout)?;
+        drop(connect_span);
+
+        // the target only, the credentials (or the circuit isolation ones derived per contact) stay out
+        let _span = tracing::debug_span!("proxy-handshake", proxy_type = %self.proxy.proxy_type, target = %target).entered();
+        let credentials = self.proxy.username.as_ref()
+            .map(|u| (u.as_str(), self.proxy.password.as_ref().map(|p| p.as_str()).unwrap_or("")));
+
+        // boxed into the io::Error so `requests::request_error` can tell an auth failure apart
+        socks5_handshake(&mut stream, credentials, &target).map_err(|e| match e {
+            Socks5Error::Io(e) => e,
+            e => io::Error::other(e),
+        })?;
+
+        Ok(Some(StreamTransport::new(stream, details.config)?))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, TcpListener};
+    use std::thread;
+
+    /// A SOCKS5 proxy that answers the greeting with `method` and, when that is username/password,
+    /// the subnegotiation with `auth_status`. Returns what the client sent.
+    fn mock_socks5(method: u8, auth_status: u8) -> (TcpStream, thread::JoinHandle<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = thread::spawn(move || {
+            let (mut s, _) = listener.accept().unwrap();
+            let mut seen = Vec::new();
+
+            let mut head = [0u8; 2];
+            s.read_exact(&mut head).unwrap();
+            let mut methods = vec![0u8; head[1] as usize];
+            s.read_exact(&mut methods).unwrap();
+            seen.extend_from_slice(&head);
+            seen.extend_from_slice(&methods);
+            s.write_all(&[0x05, method]).unwrap();
+
+            if method == 0x02 {
+                let mut version_ulen = [0u8; 2];
+                s.read_exact(&mut version_ulen).unwrap();
+                let mut user = vec![0u8; version_ulen[1] as usize + 1];
+                s.read_exact(&mut user).unwrap();
+                let mut pass = vec![0u8; user[user.len() - 1] as usize];
+                s.read_exact(&mut pass).unwrap();
+                seen.extend_from_slice(&version_ulen);
+                seen.extend_from_slice(&user);
+                seen.extend_from_slice(&pass);
+                s.write_all(&[0x01, auth_status]).unwrap();
+                if auth_status != 0x00 {
+                    return seen;
+                }
+            } else if method == 0xFF {
+                return seen;
+            }
+
+            let mut request = [0u8; 4 + 4 + 2];
+            s.read_exact(&mut request).unwrap();
+            seen.extend_from_slice(&request);
+            s.write_all(&[0x05, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0]).unwrap();
+
+            seen
+        });
+
+        (TcpStream::connect(addr).unwrap(), server)
+    }
+
+    fn target() -> Socks5Target {
+        Socks5Target::Addr(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 443))
+    }
+
+    #[test]
+    fn test_socks5_handshake_without_credentials() {
+        let (mut stream, server) = mock_socks5(0x00, 0x00);
+
+        socks5_handshake(&mut stream, None, &target()).unwrap();
+
+        let seen = server.join().unwrap();
+        assert_eq!(seen[..3], [0x05, 0x01, 0x00]);
+        assert_eq!(seen[3..], [0x05, 0x01, 0x00, 0x01, 10, 0, 0, 1, 0x01, 0xbb]);
+    }
+
+    #[test]
+    fn test_socks5_handshake_with_credentials() {
+        let (mut stream, server) = mock_socks5(0x02, 0x00);
+
+        socks5_handshake(&mut stream, Some(("alice", "s3cret")), &target()).unwrap();
+
+        let seen = server.join().unwrap();
+        assert_eq!(seen[..4], [0x05, 0x02, 0x00, 0x02]);
+        assert_eq!(seen[4..18], *b"\x01\x05alice\x06s3cret");
+        assert_eq!(seen[18..], [0x05, 0x01, 0x00, 0x01, 10, 0, 0, 1, 0x01, 0xbb]);
+    }
+
+    #[test]
+    fn test_socks5_handshake_wrong_password() {
+        let (mut stream, server) = mock_socks5(0x02, 0x01);
+
+        let err = socks5_handshake(&mut stream, Some(("alice", "wrong")), &target()).unwrap_err();
+        assert!(matches!(err, Socks5Error::AuthRejected));
+        assert_eq!(err.to_string(), "SOCKS5 proxy rejected the username or password");
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_socks5_handshake_method_rejected() {
+        let (mut stream, server) = mock_socks5(0xFF, 0x00);
+        let err = socks5_handshake(&mut stream, Some(("alice", "s3cret")), &target()).unwrap_err();
+        assert!(matches!(err, Socks5Error::NoAcceptableMethod));
+        server.join().unwrap();
+
+        // a proxy that insists on credentials we don't have
+        let (mut stream, server) = mock_socks5(0xFF, 0x00);
+        let err = socks5_handshake(&mut stream, None, &target()).unwrap_err();
+        assert!(matches!(err, Socks5Error::NoAcceptableMethod));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_socks5_handshake_domain_target() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = thread::spawn(move || {
+            let (mut s, _) = listener.accept().unwrap();
+            let mut greeting = [0u8; 3];
+            s.read_exact(&mut greeting).unwrap();
+            s.write_all(&[0x05, 0x00]).unwrap();
+
+            let mut head = [0u8; 5];
+            s.read_exact(&mut head).unwrap();
+            let mut host = vec![0u8; head[4] as usize + 2];
+            s.read_exact(&mut host).unwrap();
+            s.write_all(&[0x05, 0x04, 0x00, 0x01, 0, 0, 0, 0, 0, 0]).unwrap();
+
+            (head, host)
+        });
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let err = socks5_handshake(&mut stream, None, &Socks5Target::Domain(String::from("relay.example.org"), 8443)).unwrap_err();
+        assert!(matches!(err, Socks5Error::ConnectFailed(0x04)));
+
+        let (head, host) = server.join().unwrap();
+        assert_eq!(head[..4], [0x05, 0x01, 0x00, 0x03]);
+        assert_eq!(&host[..host.len() - 2], b"relay.example.org");
+        assert_eq!(host[host.len() - 2..], 8443u16.to_be_bytes());
+    }
+}
@@ -0,0 +1,637 @@
+use std::fmt;
+use std::str::FromStr;
+
+use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64_STANDARD};
+use serde::{Deserialize, Serialize};
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::consts;
+use crate::error::{ConfigError, Error};
+use crate::utils;
+
+
+/// One entry of the state body: a tag like "contact" and its raw value.
+pub type Record = (String, Zeroizing<Vec<u8>>);
+
+
+/// How the records of the state body are encoded, named by the `format:` header line (`--state-format`).
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StateFormat {
+    /// `tag:base64` lines, what every state file before version 3 holds. Read, never written.
+    Text,
+    /// Readable JSON for debugging, values that are text are stored as is.
+    Json,
+    /// Length-prefixed records, the compact default.
+    Binary,
+}
+
+impl FromStr for StateFormat {
+    type Err = ConfigError;
+
+    /// Parse a `--state-format` value, "bincode" is accepted for binary.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "json" => Ok(StateFormat::Json),
+            "binary" | "bincode" => Ok(StateFormat::Binary),
+            _ => Err(ConfigError::InvalidStateFormat(s.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for StateFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            StateFormat::Text => "text",
+            StateFormat::Json => "json",
+            StateFormat::Binary => "binary",
+        })
+    }
+}
+
+
+/// Decrypted state file content, split into its schema version, its encoding and the records that follow.
+pub struct VersionedState {
+    pub version: u32,
+    pub format: StateFormat,
+    pub body: Zeroizing<Vec<u8>>,
+}
+
+
+/// Split the leading `version:N` line, and from version 3 on the `format:` line, off decrypted state content.
+///
+/// State files written before versioning have no such line and are reported as version 0.
+pub fn split_version(plaintext: &[u8]) -> Result<VersionedState, Error> {
+    let (first_line, rest) = split_line(plaintext);
+
+    let version = match first_line.strip_prefix(b"version:") {
+        Some(v) => std::str::from_utf8(v)
+            .ok()
+            .and_then(|v| v.trim().parse::<u32>().ok())
+            .ok_or(Error::StateFileCorrupted)?,
+        None => return Ok(VersionedState { version: 0, format: StateFormat::Text, body: Zeroizing::new(plaintext.to_vec()) }),
+    };
+
+    // a newer client's header may look different, `migrate_state` refuses it by its version
+    if version < 3 || version > consts::STATE_FILE_VERSION {
+        return Ok(VersionedState { version, format: StateFormat::Text, body: Zeroizing::new(rest.to_vec()) });
+    }
+
+    let (format_line, body) = split_line(rest);
+    let format = match format_line.strip_prefix(b"format:") {
+        Some(b"json") => StateFormat::Json,
+        Some(b"binary") => StateFormat::Binary,
+        Some(b"text") => StateFormat::Text,
+        _ => return Err(Error::StateFileCorrupted),
+    };
+
+    Ok(VersionedState { version, format, body: Zeroizing::new(body.to_vec()) })
+}
+
+/// The bytes before the first '\n' and the ones after it.
+fn split_line(bytes: &[u8]) -> (&[u8], &[u8]) {
+    match bytes.iter().position(|b| *b == b'\n') {
+        Some(i) => (&bytes[..i], &bytes[i + 1..]),
+        None => (bytes, &[]),
+    }
+}
+
+
+/// Encode the records in `format` behind the current schema version and format lines, before the state
+/// is encrypted.
+pub fn encode_state(records: &[Record], format: StateFormat) -> Result<Zeroizing<Vec<u8>>, Error> {
+    let header = format!("version:{}\nformat:{}\n", consts::STATE_FILE_VERSION, format);
+
+    let mut out = Zeroizing::new(Vec::with_capacity(header.len() + records.iter().map(|(t, v)| t.len() + v.len() + 8).sum::<usize>()));
+    out.extend_from_slice(header.as_bytes());
+
+    match format {
+        StateFormat::Text => {
+            for (i, (tag, value)) in records.iter().enumerate() {
+                if i > 0 {
+                    out.push(b'\n');
+                }
+                out.extend_from_slice(tag.as_bytes());
+                out.push(b':');
+                out.extend_from_slice(Zeroizing::new(BASE64_STANDARD.encode(value.as_slice())).as_bytes());
+            }
+        }
+        StateFormat::Json => {
+            let records: Vec<JsonRecord> = records.iter().map(|(tag, value)| JsonRecord::new(tag, value)).collect();
+            serde_json::to_writer_pretty(&mut *out, &JsonState { records })
+                .map_err(|_| Error::StateFileCorrupted)?;
+        }
+        StateFormat::Binary => {
+            for (tag, value) in records {
+                let tag_len = u8::try_from(tag.len()).map_err(|_| Error::StateFileCorrupted)?;
+                let value_len = u32::try_from(value.len()).map_err(|_| Error::StateFileCorrupted)?;
+
+                out.push(tag_len);
+                out.extend_from_slice(tag.as_bytes());
+                out.extend_from_slice(&value_len.to_be_bytes());
+                out.extend_from_slice(value);
+            }
+        }
+    }
+
+    Ok(out)
+}
+
+
+/// Decode the records of a state body in the format its header named.
+pub fn decode_records(state: &VersionedState) -> Result<Vec<Record>, Error> {
+    let body = state.body.as_slice();
+    let mut records = Vec::new();
+
+    match state.format {
+        StateFormat::Text => {
+            let text = std::str::from_utf8(body)
+                .map_err(|_| Error::FailedToConvertBytesToUtf8)?;
+
+            for line in text.lines() {
+                // skip empty lines
+                if line.trim().is_empty() {
+                    continue;
+                }
+
+                let (tag, b64) = line.split_once(':')
+                    .ok_or(Error::FailedToSplitLineOnce)?;
+
+                let value = Zeroizing::new(BASE64_STANDARD.decode(b64)
+                    .map_err(|_| Error::FailedToDecodeBase64)?);
+
+                records.push((tag.to_string(), value));
+            }
+        }
+        StateFormat::Json => {
+            let mut state: JsonState = serde_json::from_slice(body)
+                .map_err(|_| Error::StateFileCorrupted)?;
+
+            for record in state.records.iter_mut() {
+                let value = match (record.text.take(), record.base64.take()) {
+                    (Some(text), None) => Zeroizing::new(Zeroizing::new(text).as_bytes().to_vec()),
+                    (None, Some(b64)) => Zeroizing::new(BASE64_STANDARD.decode(Zeroizing::new(b64).as_bytes())
+                        .map_err(|_| Error::FailedToDecodeBase64)?),
+                    _ => return Err(Error::StateFileCorrupted),
+                };
+
+                records.push((std::mem::take(&mut record.tag), value));
+            }
+        }
+        StateFormat::Binary => {
+            let mut rest = body;
+
+            while !rest.is_empty() {
+                let tag_len = rest[0] as usize;
+                let tag = rest.get(1..1 + tag_len).ok_or(Error::StateFileCorrupted)?;
+                rest = &rest[1 + tag_len..];
+
+                let value_len = rest.get(..4)
+                    .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]) as usize)
+                    .ok_or(Error::StateFileCorrupted)?;
+                let value = rest.get(4..4 + value_len).ok_or(Error::StateFileCorrupted)?;
+                rest = &rest[4 + value_len..];
+
+                let tag = std::str::from_utf8(tag).map_err(|_| Error::StateFileCorrupted)?;
+                records.push((tag.to_string(), Zeroizing::new(value.to_vec())));
+            }
+        }
+    }
+
+    Ok(records)
+}
+
+
+#[derive(Serialize, Deserialize)]
+struct JsonState {
+    records: Vec<JsonRecord>,
+}
+
+/// A record with either `text` (printable UTF-8 values like the server URL) or `base64` (keys, contacts).
+#[derive(Serialize, Deserialize, Zeroize)]
+#[zeroize(drop)]
+struct JsonRecord {
+    tag: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    text: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    base64: Option<String>,
+}
+
+impl JsonRecord {
+    fn new(tag: &str, value: &[u8]) -> Self {
+        match std::str::from_utf8(value) {
+            Ok(text) if !text.chars().any(char::is_control) => {
+                JsonRecord { tag: tag.to_string(), text: Some(text.to_string()), base64: None }
+            }
+            _ => JsonRecord { tag: tag.to_string(), text: None, base64: Some(BASE64_STANDARD.encode(value)) },
+        }
+    }
+}
+
+
+/// Upgrade state written by an older client to the current schema, one version at a time.
+///
+/// State from a newer client is refused rather than guessed at.
+pub fn migrate_state(mut state: VersionedState) -> Result<VersionedState, Error> {
+    if state.version > consts::STATE_FILE_VERSION {
+        return Err(Error::UnsupportedStateFileVersion);
+    }
+
+    while state.version < consts::STATE_FILE_VERSION {
+        state = match state.version {
+            0 => migrate_v0_to_v1(state),
+            1 => migrate_v1_to_v2(state),
+            2 => migrate_v2_to_v3(state),
+            _ => return Err(Error::UnsupportedStateFileVersion),
+        };
+    }
+
+    Ok(state)
+}
+
+
+/// Version 1 only added the version line itself, the body is unchanged.
+fn migrate_v0_to_v1(state: VersionedState) -> VersionedState {
+    VersionedState { version: 1, format: state.format, body: state.body }
+}
+
+
+/// Version 2 added the `queued` and `queue_next_id` tags of the outbound queue, a version 1 body has none.
+fn migrate_v1_to_v2(state: VersionedState) -> VersionedState {
+    VersionedState { version: 2, format: state.format, body: state.body }
+}
+
+
+/// Version 3 added the `format:` header line, older bodies stay in the text format they were written in
+/// and are rewritten in `--state-format` on the next save.
+fn migrate_v2_to_v3(state: VersionedState) -> VersionedState {
+    VersionedState { version: 3, format: state.format, body: state.body }
+}
+
+
+/// An already encrypted message blob for one recipient, waiting in the state file for the relay to come back.
+#[derive(Zeroize)]
+pub struct PendingMessage {
+    /// Assigned from `OutboundQueue::next_id`, never reused, so the send order survives restarts.
+    pub id: u64,
+    /// Unix time in seconds when the message was queued.
+    pub queued_at: u64,
+    pub recipient: String,
+    pub blob: Zeroizing<Vec<u8>>,
+}
+
+impl PendingMessage {
+    /// `id:queued_at:recipient:` followed by the raw blob, stored base64-encoded under the `queued` tag.
+    ///
+    /// The recipient is percent-encoded (`utils::percent_encode`), an `id@relay:8443` would otherwise split
+    /// into one field too many. Recipients written before were plain `digits@domain` and decode unchanged.
+    pub fn encode(&self) -> Zeroizing<Vec<u8>> {
+        let header = format!("{}:{}:{}:", self.id, self.queued_at, utils::percent_encode(&self.recipient).as_str());
+
+        let mut out = Zeroizing::new(Vec::with_capacity(header.len() + self.blob.len()));
+        out.extend_from_slice(header.as_bytes());
+        out.extend_from_slice(&self.blob);
+        out
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, Error> {
+        let mut fields = bytes.splitn(4, |b| *b == b':');
+
+        let mut next_text = || fields.next()
+            .and_then(|f| std::str::from_utf8(f).ok())
+            .map(str::to_string)
+            .ok_or(Error::StateFileCorrupted);
+
+        let id = next_text()?.parse().map_err(|_| Error::StateFileCorrupted)?;
+        let queued_at = next_text()?.parse().map_err(|_| Error::StateFileCorrupted)?;
+        let recipient = utils::percent_decode(&next_text()?).ok_or(Error::StateFileCorrupted)?.to_string();
+        let blob = fields.next().ok_or(Error::StateFileCorrupted)?;
+
+        if recipient.is_empty() {
+            return Err(Error::StateFileCorrupted);
+        }
+
+        Ok(PendingMessage { id, queued_at, recipient, blob: Zeroizing::new(blob.to_vec()) })
+    }
+}
+
+
+/// Messages that could not be sent yet, oldest first, persisted in the state file.
+#[derive(Zeroize, Default)]
+pub struct OutboundQueue {
+    items: Vec<PendingMessage>,
+    /// Id of the next queued message, stored in the state file so ids stay unique after the queue drained.
+    next_id: u64,
+}
+
+impl OutboundQueue {
+    /// Append a message for `recipient`, failing with `OutboundQueueFull` once `max` messages are waiting.
+    pub fn push(&mut self, recipient: &str, blob: Zeroizing<Vec<u8>>, queued_at: u64, max: usize) -> Result<u64, Error> {
+        if self.items.len() >= max {
+            return Err(Error::OutboundQueueFull);
+        }
+
+        let id = self.next_id;
+        self.next_id += 1;
+        self.items.push(PendingMessage { id, queued_at, recipient: recipient.to_string(), blob });
+
+        Ok(id)
+    }
+
+    /// Re-add a message read back from the state file. A second copy of an id is dropped, so a message is
+    /// never sent twice even if the file holds it twice.
+    pub fn restore(&mut self, message: PendingMessage) {
+        self.next_id = self.next_id.max(message.id + 1);
+
+        if let Err(i) = self.items.binary_search_by_key(&message.id, |m| m.id) {
+            self.items.insert(i, message);
+        }
+    }
+
+    /// Continue numbering at `next_id` (from the state file) unless a restored message is numbered higher.
+    pub fn restore_next_id(&mut self, next_id: u64) {
+        self.next_id = self.next_id.max(next_id);
+    }
+
+    pub fn next_id(&self) -> u64 {
+        self.next_id
+    }
+
+    /// The oldest message, the next one to send.
+    pub fn front(&self) -> Option<&PendingMessage> {
+        self.items.first()
+    }
+
+    /// Drop the message with `id` once the relay accepted it.
+    pub fn remove(&mut self, id: u64) {
+        self.items.retain(|m| m.id != id);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &PendingMessage> {
+        self.items.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+}
+
+
+/// What `inspect` reports about a state file, public identifiers and fingerprints only, never key material
+/// or message contents.
+#[derive(Debug, Serialize)]
+pub struct StateSummary {
+    pub path: String,
+    /// Schema version the file was written with, before any in-memory migration.
+    pub version: u32,
+    pub current_version: u32,
+    pub format: StateFormat,
+    /// When the file was last saved, the client does not record sync times of its own.
+    pub last_modified: Option<String>,
+    pub server_url: Option<String>,
+    pub user_id: Option<String>,
+    pub auth_key_fingerprint: Option<String>,
+    pub contacts: Vec<ContactSummary>,
+    pub queued_messages: usize,
+    /// When the oldest queued message was queued, as an RFC 3339 UTC timestamp.
+    pub oldest_queued_at: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ContactSummary {
+    pub id: String,
+    pub verified: bool,
+}
+
+impl StateSummary {
+    pub fn to_human(&self) -> String {
+        let mut out = String::new();
+
+        out.push_str(&format!("state file: {}\n", self.path));
+        if self.version < self.current_version {
+            out.push_str(&format!("schema version: {} (upgraded to {} on the next start)\n", self.version, self.current_version));
+        } else {
+            out.push_str(&format!("schema version: {}\n", self.version));
+        }
+        out.push_str(&format!("format: {}\n", self.format));
+        out.push_str(&format!("last modified: {}\n", self.last_modified.as_deref().unwrap_or("unknown")));
+        out.push_str(&format!("server: {}\n", self.server_url.as_deref().unwrap_or("(none)")));
+        out.push_str(&format!("user id: {}\n", self.user_id.as_deref().unwrap_or("(not registered yet)")));
+        out.push_str(&format!("auth key: {}\n", self.auth_key_fingerprint.as_deref().unwrap_or("(none)")));
+
+        let verified = self.contacts.iter().filter(|c| c.verified).count();
+        out.push_str(&format!("contacts: {} ({} verified)\n", self.contacts.len(), verified));
+        for contact in &self.contacts {
+            out.push_str(&format!("  {} ({})\n", contact.id, if contact.verified { "Verified" } else { "Pending verification" }));
+        }
+
+        match &self.oldest_queued_at {
+            Some(at) => out.push_str(&format!("queued messages: {} (oldest from {})", self.queued_messages, at)),
+            None => out.push_str(&format!("queued messages: {}", self.queued_messages)),
+        }
+
+        out
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(tag: &str, value: &[u8]) -> Record {
+        (tag.to_string(), Zeroizing::new(value.to_vec()))
+    }
+
+    #[test]
+    fn test_state_version_round_trip() {
+        let encoded = encode_state(&[record("user_id", b"alice")], StateFormat::Text).unwrap();
+        let state = split_version(&encoded).unwrap();
+
+        assert_eq!(state.version, consts::STATE_FILE_VERSION);
+        assert_eq!(state.format, StateFormat::Text);
+        assert_eq!(state.body.as_slice(), b"user_id:YWxpY2U=");
+    }
+
+    #[test]
+    fn test_state_format_round_trip() {
+        let records = vec![
+            record("server_url", b"https://relay.example.org/"),
+            record("auth_secret_key", &[0, 1, 2, 0xff, b'\n']),
+            record("contact", b""),
+            record("queue_next_id", b"7"),
+        ];
+
+        for format in [StateFormat::Text, StateFormat::Json, StateFormat::Binary] {
+            let encoded = encode_state(&records, format).unwrap();
+            let state = migrate_state(split_version(&encoded).unwrap()).unwrap();
+            assert_eq!(state.format, format);
+
+            let decoded = decode_records(&state).unwrap();
+            assert_eq!(decoded.len(), records.len(), "{}", format);
+            for ((tag, value), (expected_tag, expected_value)) in decoded.iter().zip(&records) {
+                assert_eq!(tag, expected_tag, "{}", format);
+                assert_eq!(value.as_slice(), expected_value.as_slice(), "{}", format);
+            }
+        }
+
+        // JSON keeps text readable, binary values go to base64
+        let json = encode_state(&records, StateFormat::Json).unwrap();
+        let json = std::str::from_utf8(&json).unwrap();
+        assert!(json.contains("\"text\": \"https://relay.example.org/\""));
+        assert!(json.contains("\"base64\": \"AAEC/wo=\""));
+
+        // binary is the smaller one
+        assert!(encode_state(&records, StateFormat::Binary).unwrap().len() < encode_state(&records, StateFormat::Text).unwrap().len());
+    }
+
+    #[test]
+    fn test_state_format_corrupted() {
+        let truncated = encode_state(&[record("contact", b"abcdef")], StateFormat::Binary).unwrap();
+        let state = split_version(&truncated[..truncated.len() - 1]).unwrap();
+        assert!(matches!(decode_records(&state), Err(Error::StateFileCorrupted)));
+
+        let both = b"version:3\nformat:json\n{\"records\":[{\"tag\":\"user_id\",\"text\":\"a\",\"base64\":\"YQ==\"}]}";
+        assert!(matches!(decode_records(&split_version(both).unwrap()), Err(Error::StateFileCorrupted)));
+
+        assert!(matches!(split_version(b"version:3\nformat:yaml\n"), Err(Error::StateFileCorrupted)));
+        assert!(matches!(split_version(b"version:3\nuser_id:YWxpY2U="), Err(Error::StateFileCorrupted)));
+    }
+
+    #[test]
+    fn test_state_format_from_str() {
+        assert_eq!("JSON".parse::<StateFormat>().unwrap(), StateFormat::Json);
+        assert_eq!("binary".parse::<StateFormat>().unwrap(), StateFormat::Binary);
+        assert_eq!("bincode".parse::<StateFormat>().unwrap(), StateFormat::Binary);
+        assert!("text".parse::<StateFormat>().is_err());
+    }
+
+    #[test]
+    fn test_migrate_legacy_state() {
+        let state = split_version(b"server_url:aHR0cHM6Ly9yZWxheS5leGFtcGxlLm9yZy8=\nuser_id:YWxpY2U=").unwrap();
+        assert_eq!(state.version, 0);
+
+        let migrated = migrate_state(state).unwrap();
+        assert_eq!(migrated.version, consts::STATE_FILE_VERSION);
+        assert_eq!(migrated.format, StateFormat::Text);
+        assert_eq!(migrated.body.as_slice(), b"server_url:aHR0cHM6Ly9yZWxheS5leGFtcGxlLm9yZy8=\nuser_id:YWxpY2U=");
+
+        let records = decode_records(&migrated).unwrap();
+        assert_eq!(records[0].0, "server_url");
+        assert_eq!(records[0].1.as_slice(), b"https://relay.example.org/");
+
+        // a version 2 file has the version line but no format line
+        let v2 = migrate_state(split_version(b"version:2\nuser_id:YWxpY2U=").unwrap()).unwrap();
+        assert_eq!(v2.version, consts::STATE_FILE_VERSION);
+        assert_eq!(decode_records(&v2).unwrap()[0].1.as_slice(), b"alice");
+    }
+
+    #[test]
+    fn test_refuse_newer_state() {
+        let newer = format!("version:{}\nuser_id:YWxpY2U=", consts::STATE_FILE_VERSION + 1);
+        let state = split_version(newer.as_bytes()).unwrap();
+
+        assert!(matches!(migrate_state(state), Err(Error::UnsupportedStateFileVersion)));
+        assert!(matches!(split_version(b"version:abc\n"), Err(Error::StateFileCorrupted)));
+    }
+
+    #[test]
+    fn test_pending_message_round_trip() {
+        let message = PendingMessage {
+            id: 7,
+            queued_at: 1_700_000_000,
+            recipient: String::from("1234567890123456"),
+            blob: Zeroizing::new(b"\x00:ciphertext:\xff".to_vec()),
+        };
+
+        let decoded = PendingMessage::decode(&message.encode()).unwrap();
+        assert_eq!(decoded.id, 7);
+        assert_eq!(decoded.queued_at, 1_700_000_000);
+        assert_eq!(decoded.recipient, "1234567890123456");
+        assert_eq!(decoded.blob.as_slice(), b"\x00:ciphertext:\xff");
+
+        let message = PendingMessage { recipient: String::from("1234567890123456@relay.example.org:8443"), ..message };
+        let encoded = message.encode();
+        assert!(encoded.starts_with(b"7:1700000000:1234567890123456%40relay.example.org%3A8443:"));
+        let decoded = PendingMessage::decode(&encoded).unwrap();
+        assert_eq!(decoded.recipient, "1234567890123456@relay.example.org:8443");
+        assert_eq!(decoded.blob.as_slice(), b"\x00:ciphertext:\xff");
+
+        // written before the recipient was encoded
+        let decoded = PendingMessage::decode(b"3:1:1234567890123456@relay.example.org:blob").unwrap();
+        assert_eq!(decoded.recipient, "1234567890123456@relay.example.org");
+
+        for bad in [&b"7:1700000000:"[..], b"x:1:alice:blob", b"7:1::blob", b"7", b"7:1:al%zzice:blob"] {
+            assert!(matches!(PendingMessage::decode(bad), Err(Error::StateFileCorrupted)));
+        }
+    }
+
+    #[test]
+    fn test_outbound_queue_order_and_limit() {
+        let mut queue = OutboundQueue::default();
+
+        assert_eq!(queue.push("alice", Zeroizing::new(vec![1]), 10, 2).unwrap(), 0);
+        assert_eq!(queue.push("bob", Zeroizing::new(vec![2]), 11, 2).unwrap(), 1);
+        assert!(matches!(queue.push("carol", Zeroizing::new(vec![3]), 12, 2), Err(Error::OutboundQueueFull)));
+
+        queue.remove(0);
+        assert_eq!(queue.front().unwrap().recipient, "bob");
+
+        // ids keep counting up after the queue drained
+        queue.remove(1);
+        assert!(queue.is_empty());
+        assert_eq!(queue.push("carol", Zeroizing::new(vec![3]), 12, 2).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_state_summary_human() {
+        let summary = StateSummary {
+            path: String::from("/tmp/state"),
+            version: 1,
+            current_version: 2,
+            format: StateFormat::Text,
+            last_modified: Some(String::from("2024-05-01T13:37:00Z")),
+            server_url: Some(String::from("https://relay.example.org/")),
+            user_id: Some(String::from("1234567890123456")),
+            auth_key_fingerprint: Some(String::from("sha256/abc=")),
+            contacts: vec![
+                ContactSummary { id: String::from("1111111111111111"), verified: true },
+                ContactSummary { id: String::from("2222222222222222"), verified: false },
+            ],
+            queued_messages: 0,
+            oldest_queued_at: None,
+        };
+
+        assert_eq!(summary.to_human(), "\
+state file: /tmp/state
+schema version: 1 (upgraded to 2 on the next start)
+format: text
+last modified: 2024-05-01T13:37:00Z
+server: https://relay.example.org/
+user id: 1234567890123456
+auth key: sha256/abc=
+contacts: 2 (1 verified)
+  1111111111111111 (Verified)
+  2222222222222222 (Pending verification)
+queued messages: 0");
+    }
+
+    #[test]
+    fn test_outbound_queue_restore() {
+        let message = |id| PendingMessage { id, queued_at: 0, recipient: String::from("alice"), blob: Zeroizing::new(vec![id as u8]) };
+
+        let mut queue = OutboundQueue::default();
+        queue.restore_next_id(3);
+        queue.restore(message(5));
+        queue.restore(message(2));
+        queue.restore(message(5));
+
+        assert_eq!(queue.iter().map(|m| m.id).collect::<Vec<_>>(), vec![2, 5]);
+        assert_eq!(queue.next_id(), 6);
+    }
+}
@@ -0,0 +1,178 @@
+use std::sync::{Arc, OnceLock};
+
+use rustls::client::WebPkiServerVerifier;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{CryptoProvider, verify_tls12_signature, verify_tls13_signature};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{CertificateError, ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use ureq::tls::RootCerts;
+
+use crate::crypto;
+use crate::error::Error;
+use crate::requests::TlsVersion;
+
+
+static TLS13_ONLY: &[&rustls::SupportedProtocolVersion] = &[&rustls::version::TLS13];
+
+
+/// The rustls config of our TLS connector (`transport::TlsConnector`), ureq's own `TlsConfig` has no
+/// hook for a custom certificate verifier.
+///
+/// Server certificates are checked against the bundled Mozilla roots and `root_certs` (`--ca-file`), then
+/// the leaf's public key against `pins` (`--pin-sha256`). A `min_tls` of 1.3 leaves TLS 1.2 out of the
+/// offered versions, so a server that only speaks 1.2 fails the handshake.
+pub(crate) fn client_config(root_certs: Option<&RootCerts>, insecure_skip_verify: bool, min_tls: TlsVersion, pins: &[[u8; 32]]) -> Result<Arc<ClientConfig>, Error> {
+    let provider = provider();
+    let versions: &[&rustls::SupportedProtocolVersion] = match min_tls {
+        TlsVersion::Tls12 => rustls::ALL_VERSIONS,
+        TlsVersion::Tls13 => TLS13_ONLY,
+    };
+
+    let verifier: Arc<dyn ServerCertVerifier> = if insecure_skip_verify {
+        Arc::new(SkipVerification(provider.clone()))
+    } else {
+        let roots = root_store(root_certs);
+        let webpki = WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+            .build()
+            .map_err(|_| Error::FailedToConfigureTls)?;
+
+        Arc::new(PinnedVerifier { webpki, pins: pins.to_vec() })
+    };
+
+    let config = ClientConfig::builder_with_provider(provider)
+        .with_protocol_versions(versions)
+        .map_err(|_| Error::FailedToConfigureTls)?
+        .dangerous()
+        .with_custom_certificate_verifier(verifier)
+        .with_no_client_auth();
+
+    Ok(Arc::new(config))
+}
+
+
+fn provider() -> Arc<CryptoProvider> {
+    static PROVIDER: OnceLock<Arc<CryptoProvider>> = OnceLock::new();
+
+    PROVIDER.get_or_init(|| Arc::new(rustls::crypto::ring::default_provider())).clone()
+}
+
+
+/// The Mozilla roots, plus the `--ca-file` roots when given: a private CA is trusted in addition to the
+/// public ones, not instead of them.
+fn root_store(root_certs: Option<&RootCerts>) -> RootCertStore {
+    let mut store = RootCertStore { roots: webpki_roots::TLS_SERVER_ROOTS.to_vec() };
+
+    if let Some(RootCerts::Specific(certs)) = root_certs {
+        store.add_parsable_certificates(certs.iter().map(|c| CertificateDer::from(c.der())));
+    }
+
+    store
+}
+
+
+/// CA validation, then the `--pin-sha256` check of the leaf's SubjectPublicKeyInfo.
+///
+/// Pins are skipped for .onion hosts, Tor authenticates those by their address already.
+#[derive(Debug)]
+struct PinnedVerifier {
+    webpki: Arc<WebPkiServerVerifier>,
+    pins: Vec<[u8; 32]>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self.webpki.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)?;
+
+        if self.pins.is_empty() || is_onion(server_name) {
+            return Ok(verified);
+        }
+
+        let leaf = webpki::EndEntityCert::try_from(end_entity).map_err(|_| rustls::Error::InvalidCertificate(CertificateError::BadEncoding))?;
+        if !crypto::spki_matches_pins(&self.pins, &leaf.subject_public_key_info()) {
+            tracing::warn!(server = %server_name.to_str(), "certificate matches none of the --pin-sha256 pins");
+            return Err(rustls::Error::InvalidCertificate(CertificateError::ApplicationVerificationFailure));
+        }
+
+        Ok(verified)
+    }
+
+    fn verify_tls12_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.webpki.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.webpki.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.webpki.supported_verify_schemes()
+    }
+}
+
+
+fn is_onion(server_name: &ServerName<'_>) -> bool {
+    matches!(server_name, ServerName::DnsName(name) if name.as_ref().to_ascii_lowercase().ends_with(".onion"))
+}
+
+
+/// `--insecure-skip-verify`: any certificate is accepted, the handshake signatures are still checked so
+/// the connection at least ends at whoever holds the key of the certificate it sent.
+#[derive(Debug)]
+struct SkipVerification(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for SkipVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ca_file_keeps_mozilla_roots() {
+        let certified = rcgen::generate_simple_self_signed(vec![String::from("relay.internal")]).unwrap();
+        let ca_file = RootCerts::new_with_certs(&[ureq::tls::Certificate::from_der(certified.cert.der()).to_owned()]);
+
+        let store = root_store(Some(&ca_file));
+        assert_eq!(store.len(), webpki_roots::TLS_SERVER_ROOTS.len() + 1);
+
+        // a chain to any public CA still validates next to the private one
+        for anchor in webpki_roots::TLS_SERVER_ROOTS {
+            assert!(store.roots.contains(anchor));
+        }
+
+        let private = webpki::anchor_from_trusted_cert(certified.cert.der()).unwrap();
+        assert!(store.roots.contains(&private.to_owned()));
+
+        assert_eq!(root_store(None).len(), webpki_roots::TLS_SERVER_ROOTS.len());
+    }
+}
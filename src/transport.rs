@@ -0,0 +1,172 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+
+use rustls::pki_types::ServerName;
+use rustls::{ClientConfig, ClientConnection, StreamOwned};
+use ureq::config::Config as AgentConfig;
+use ureq::unversioned::transport::{Buffers, ConnectionDetails, Connector, Either, LazyBuffers, NextTimeout, Transport, TransportAdapter};
+
+use crate::trace;
+
+
+/// A connected socket handed to ureq by our own connectors (`DirectConnector`, `Socks5Connector`), the
+/// same as ureq's `TcpTransport` which is not public.
+pub(crate) struct StreamTransport {
+    stream: TcpStream,
+    buffers: LazyBuffers,
+}
+
+impl StreamTransport {
+    pub(crate) fn new(stream: TcpStream, config: &AgentConfig) -> io::Result<Self> {
+        if config.no_delay() {
+            stream.set_nodelay(true)?;
+        }
+
+        let buffers = LazyBuffers::new(config.input_buffer_size(), config.output_buffer_size());
+        Ok(StreamTransport { stream, buffers })
+    }
+}
+
+impl fmt::Debug for StreamTransport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StreamTransport")
+            .field("local", &self.stream.local_addr().ok())
+            .field("peer", &self.stream.peer_addr().ok())
+            .finish()
+    }
+}
+
+impl Transport for StreamTransport {
+    fn buffers(&mut self) -> &mut dyn Buffers {
+        &mut self.buffers
+    }
+
+    fn transmit_output(&mut self, amount: usize, timeout: NextTimeout) -> Result<(), ureq::Error> {
+        self.stream.set_write_timeout(timeout.not_zero().map(|t| *t))?;
+
+        let output = &self.buffers.output()[..amount];
+        self.stream.write_all(output).map_err(|e| io_error(e, timeout))
+    }
+
+    fn await_input(&mut self, timeout: NextTimeout) -> Result<bool, ureq::Error> {
+        self.stream.set_read_timeout(timeout.not_zero().map(|t| *t))?;
+
+        let input = self.buffers.input_append_buf();
+        let amount = self.stream.read(input).map_err(|e| io_error(e, timeout))?;
+        self.buffers.input_appended(amount);
+
+        Ok(amount > 0)
+    }
+
+    fn is_open(&mut self) -> bool {
+        // a pooled connection the server closed reads EOF right away, an idle open one would block
+        if self.stream.set_nonblocking(true).is_err() {
+            return false;
+        }
+
+        let open = matches!(self.stream.read(&mut [0]), Err(e) if e.kind() == io::ErrorKind::WouldBlock);
+
+        self.stream.set_nonblocking(false).is_ok() && open
+    }
+}
+
+
+/// TLS over the chained transport with our own rustls config (`tls::client_config`), chained after
+/// `DirectConnector`, `Socks5Connector` and the proxy connectors. It stands in for ureq's
+/// `RustlsConnector`, which can't take a custom certificate verifier for `--pin-sha256`.
+#[derive(Debug)]
+pub(crate) struct TlsConnector {
+    config: Arc<ClientConfig>,
+}
+
+impl TlsConnector {
+    pub(crate) fn new(config: Arc<ClientConfig>) -> Self {
+        TlsConnector { config }
+    }
+}
+
+impl<In: Transport> Connector<In> for TlsConnector {
+    type Out = Either<In, TlsTransport>;
+
+    fn connect(&self, details: &ConnectionDetails, chained: Option<In>) -> Result<Option<Self::Out>, ureq::Error> {
+        let Some(transport) = chained else {
+            return Ok(None);
+        };
+
+        // plain http:// passes through untouched, no handshake to time
+        if !details.needs_tls() || transport.is_tls() {
+            return Ok(Some(Either::A(transport)));
+        }
+
+        let _span = tracing::debug_span!("tls", server = %trace::uri_target(&details.uri)).entered();
+
+        let host = details.uri.host().ok_or(ureq::Error::Tls("missing host for tls"))?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let name = ServerName::try_from(host).map_err(|_| ureq::Error::Tls("invalid dns name for tls"))?.to_owned();
+
+        let conn = ClientConnection::new(self.config.clone(), name)?;
+        let stream = StreamOwned { conn, sock: TransportAdapter::new(transport.boxed()) };
+        let buffers = LazyBuffers::new(details.config.input_buffer_size(), details.config.output_buffer_size());
+
+        Ok(Some(Either::B(TlsTransport { stream, buffers })))
+    }
+}
+
+
+/// A TLS session opened by `TlsConnector`, the same as ureq's `RustlsTransport`.
+pub(crate) struct TlsTransport {
+    stream: StreamOwned<ClientConnection, TransportAdapter>,
+    buffers: LazyBuffers,
+}
+
+impl fmt::Debug for TlsTransport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsTransport")
+            .field("inner", &self.stream.sock.inner())
+            .finish()
+    }
+}
+
+impl Transport for TlsTransport {
+    fn buffers(&mut self) -> &mut dyn Buffers {
+        &mut self.buffers
+    }
+
+    fn transmit_output(&mut self, amount: usize, timeout: NextTimeout) -> Result<(), ureq::Error> {
+        self.stream.get_mut().set_timeout(timeout);
+
+        let output = &self.buffers.output()[..amount];
+        self.stream.write_all(output)?;
+
+        Ok(())
+    }
+
+    fn await_input(&mut self, timeout: NextTimeout) -> Result<bool, ureq::Error> {
+        self.stream.get_mut().set_timeout(timeout);
+
+        let input = self.buffers.input_append_buf();
+        let amount = self.stream.read(input)?;
+        self.buffers.input_appended(amount);
+
+        Ok(amount > 0)
+    }
+
+    fn is_open(&mut self) -> bool {
+        self.stream.get_mut().get_mut().is_open()
+    }
+
+    fn is_tls(&self) -> bool {
+        true
+    }
+}
+
+
+/// Read and write timeouts show up as `WouldBlock` on Unix and `TimedOut` on Windows.
+fn io_error(e: io::Error, timeout: NextTimeout) -> ureq::Error {
+    match e.kind() {
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => ureq::Error::Timeout(timeout.reason),
+        _ => e.into(),
+    }
+}
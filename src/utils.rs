@@ -1,4 +1,10 @@
 use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use zeroize::{Zeroize, Zeroizing};
 
 use crate::error::Error;
 use crate::consts;
@@ -122,3 +128,882 @@ pub fn validate_identifier(identifier: &str) -> bool {
     true
 }
 
+
+/// Tests that modify the process environment hold this so they do not race each other.
+#[cfg(test)]
+pub(crate) static ENV_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+
+/// Decode `%XX` escapes (as used in URL userinfo). Returns None on a malformed escape or
+/// if the result is not valid UTF-8.
+pub fn percent_decode(input: &str) -> Option<Zeroizing<String>> {
+    let bytes = input.as_bytes();
+    let mut decoded = Zeroizing::new(Vec::with_capacity(bytes.len()));
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = bytes.get(i + 1..i + 3)?;
+            if !hex.iter().all(|b| b.is_ascii_hexdigit()) {
+                return None;
+            }
+
+            let hex = std::str::from_utf8(hex).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    let decoded = std::str::from_utf8(&decoded).ok()?;
+    Some(Zeroizing::new(decoded.to_string()))
+}
+
+
+/// Characters `percent_encode` leaves as they are: unreserved and sub-delims, the rest of what a
+/// URL userinfo may hold without escaping.
+pub fn is_userinfo_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "-._~!$&'()*+,;=".contains(c)
+}
+
+
+/// Encode everything but `is_userinfo_char` characters as `%XX`, the inverse of `percent_decode`.
+pub fn percent_encode(input: &str) -> Zeroizing<String> {
+    const HEX_DIGITS: &[u8; 16] = b"0123456789ABCDEF";
+
+    let mut encoded = Zeroizing::new(String::with_capacity(input.len()));
+
+    for c in input.chars() {
+        if is_userinfo_char(c) {
+            encoded.push(c);
+        } else {
+            let mut buf = [0u8; 4];
+            for b in c.encode_utf8(&mut buf).bytes() {
+                encoded.push('%');
+                encoded.push(char::from(HEX_DIGITS[usize::from(b >> 4)]));
+                encoded.push(char::from(HEX_DIGITS[usize::from(b & 0x0f)]));
+            }
+            buf.zeroize();
+        }
+    }
+
+    encoded
+}
+
+
+pub fn is_loopback_host(host: &str) -> bool {
+    if host.eq_ignore_ascii_case("localhost") {
+        return true;
+    }
+
+    host.parse::<std::net::IpAddr>()
+        .map(|ip| ip.is_loopback())
+        .unwrap_or(false)
+}
+
+
+/// Split a NO_PROXY value ("localhost,.example.org,10.0.0.1") into lowercase host entries.
+pub fn parse_no_proxy(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|entry| entry.trim().trim_start_matches("*.").trim_start_matches('.').to_ascii_lowercase())
+        .filter(|entry| !entry.is_empty())
+        .collect()
+}
+
+
+/// True if `host` is listed in a parsed NO_PROXY list, either exactly or as a subdomain. "*" matches every host.
+pub fn no_proxy_matches(no_proxy_hosts: &[String], host: &str) -> bool {
+    let host = host.to_ascii_lowercase();
+
+    no_proxy_hosts.iter().any(|entry| {
+        entry == "*" || host == *entry || host.strip_suffix(entry.as_str()).is_some_and(|rest| rest.ends_with('.'))
+    })
+}
+
+
+/// Convert a hostname to its ASCII form, encoding every non-ASCII label as punycode ("xn--...").
+///
+/// Labels are lowercased first. This is the RFC 3492 encoding only, not full IDNA mapping.
+pub fn host_to_ascii(host: &str) -> Option<String> {
+    let labels: Option<Vec<String>> = host
+        .split('.')
+        .map(|label| {
+            if label.is_ascii() {
+                Some(label.to_string())
+            } else {
+                punycode_encode(&label.to_lowercase()).map(|p| format!("xn--{}", p))
+            }
+        })
+        .collect();
+
+    labels.map(|l| l.join("."))
+}
+
+
+fn punycode_encode(input: &str) -> Option<String> {
+    const BASE: u32 = 36;
+    const T_MIN: u32 = 1;
+    const T_MAX: u32 = 26;
+    const INITIAL_N: u32 = 128;
+    const INITIAL_BIAS: u32 = 72;
+
+    let code_points: Vec<u32> = input.chars().map(|c| c as u32).collect();
+
+    let mut output: String = input.chars().filter(|c| c.is_ascii()).collect();
+    let basic_len = output.len() as u32;
+    if basic_len > 0 {
+        output.push('-');
+    }
+
+    let encode_digit = |d: u32| -> char {
+        if d < 26 { (b'a' + d as u8) as char } else { (b'0' + (d - 26) as u8) as char }
+    };
+
+    let mut n = INITIAL_N;
+    let mut delta: u32 = 0;
+    let mut bias = INITIAL_BIAS;
+    let mut handled = basic_len;
+
+    while (handled as usize) < code_points.len() {
+        let m = *code_points.iter().filter(|&&c| c >= n).min()?;
+        delta = delta.checked_add((m - n).checked_mul(handled + 1)?)?;
+        n = m;
+
+        for &c in &code_points {
+            if c < n {
+                delta = delta.checked_add(1)?;
+            }
+            if c == n {
+                let mut q = delta;
+                let mut k = BASE;
+                loop {
+                    let t = if k <= bias { T_MIN } else if k >= bias + T_MAX { T_MAX } else { k - bias };
+                    if q < t {
+                        break;
+                    }
+                    output.push(encode_digit(t + (q - t) % (BASE - t)));
+                    q = (q - t) / (BASE - t);
+                    k += BASE;
+                }
+                output.push(encode_digit(q));
+
+                bias = punycode_adapt(delta, handled + 1, handled == basic_len);
+                delta = 0;
+                handled += 1;
+            }
+        }
+
+        delta = delta.checked_add(1)?;
+        n += 1;
+    }
+
+    Some(output)
+}
+
+
+fn punycode_adapt(delta: u32, num_points: u32, first_time: bool) -> u32 {
+    const BASE: u32 = 36;
+    const T_MIN: u32 = 1;
+    const T_MAX: u32 = 26;
+    const SKEW: u32 = 38;
+    const DAMP: u32 = 700;
+
+    let mut delta = if first_time { delta / DAMP } else { delta / 2 };
+    delta += delta / num_points;
+
+    let mut k = 0;
+    while delta > ((BASE - T_MIN) * T_MAX) / 2 {
+        delta /= BASE - T_MIN;
+        k += BASE;
+    }
+
+    k + (((BASE - T_MIN + 1) * delta) / (delta + SKEW))
+}
+
+
+/// Expand a leading `~` to the home directory and `$VAR` / `${VAR}` references in a path.
+///
+/// Fails if a referenced variable (or the home directory) is not set, so typos don't silently create odd paths.
+pub fn expand_path(path: &str) -> Result<String, String> {
+    let mut out = String::with_capacity(path.len());
+
+    let rest = if path == "~" || path.starts_with("~/") {
+        out.push_str(&home_dir().ok_or_else(|| String::from("cannot expand '~', the home directory is not set"))?);
+        &path[1..]
+    } else if path.starts_with('~') {
+        return Err(String::from("'~user' paths are not supported, use an absolute path"));
+    } else {
+        path
+    };
+
+    let mut chars = rest.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '$' {
+            out.push(c);
+            continue;
+        }
+
+        let mut name = String::new();
+        if chars.peek() == Some(&'{') {
+            chars.next();
+            loop {
+                match chars.next() {
+                    Some('}') => break,
+                    Some(c) => name.push(c),
+                    None => return Err(String::from("unterminated '${' in path")),
+                }
+            }
+            if name.is_empty() {
+                return Err(String::from("empty '${}' in path"));
+            }
+        } else {
+            while let Some(&c) = chars.peek() {
+                if !(c.is_ascii_alphanumeric() || c == '_') {
+                    break;
+                }
+                name.push(c);
+                chars.next();
+            }
+
+            // a lone '$' is kept as is
+            if name.is_empty() {
+                out.push('$');
+                continue;
+            }
+        }
+
+        let value = std::env::var(&name)
+            .map_err(|_| format!("environment variable ${} is not set", name))?;
+        out.push_str(&value);
+    }
+
+    Ok(out)
+}
+
+
+/// Platform default for the state file when none is given:
+///   - Linux and other Unix: `$XDG_STATE_HOME/coldwire/state`, else `~/.local/state/coldwire/state`
+///   - macOS: `~/Library/Application Support/coldwire/state`
+///   - Windows: `%APPDATA%\coldwire\state`
+pub fn default_state_file_path() -> Option<String> {
+    default_app_file_path("XDG_STATE_HOME", &[".local", "state"], "state")
+}
+
+
+/// Where `coldwire-desktop setup` offers to write the config file:
+///   - Linux and other Unix: `$XDG_CONFIG_HOME/coldwire/config.toml`, else `~/.config/coldwire/config.toml`
+///   - macOS: `~/Library/Application Support/coldwire/config.toml`
+///   - Windows: `%APPDATA%\coldwire\config.toml`
+pub fn default_config_file_path() -> Option<String> {
+    default_app_file_path("XDG_CONFIG_HOME", &[".config"], "config.toml")
+}
+
+
+fn default_app_file_path(xdg_var: &str, xdg_fallback: &[&str], file_name: &str) -> Option<String> {
+    let non_empty = |name: &str| std::env::var(name).ok().filter(|v| !v.is_empty());
+
+    let base = if cfg!(windows) {
+        std::path::PathBuf::from(non_empty("APPDATA")?)
+    } else if cfg!(target_os = "macos") {
+        Path::new(&home_dir()?).join("Library").join("Application Support")
+    } else {
+        match non_empty(xdg_var) {
+            Some(dir) => std::path::PathBuf::from(dir),
+            None => xdg_fallback.iter().fold(std::path::PathBuf::from(home_dir()?), |p, c| p.join(c)),
+        }
+    };
+
+    Some(base.join("coldwire").join(file_name).to_string_lossy().into_owned())
+}
+
+
+/// Catch state file paths that can never be opened as a file, before we get a confusing OS error on the first write.
+///
+/// Returns the reason, worded to follow the flag or prompt name (e.g. "points to a directory").
+pub fn check_state_file_path(path: &str) -> Result<(), &'static str> {
+    if path.ends_with('/') || (cfg!(windows) && path.ends_with('\\')) {
+        return Err("ends with a path separator, it must name a file");
+    }
+
+    let last = path.rsplit(|c| c == '/' || (cfg!(windows) && c == '\\')).next().unwrap_or(path);
+    if last == "." || last == ".." || Path::new(path).is_dir() {
+        return Err("points to a directory");
+    }
+
+    Ok(())
+}
+
+
+/// Catch `--profile-dir` paths that can never be created as a directory, like `check_state_file_path`.
+///
+/// The directory itself is created as 0700 by `create_parent_dirs` along with the state or log file.
+pub fn check_profile_dir(path: &str) -> Result<(), &'static str> {
+    match existing_non_directory(Path::new(path)) {
+        Some(existing) if existing == Path::new(path) => Err("points to a file, it must name a directory"),
+        Some(_) => Err("is under a file, not a directory"),
+        None => Ok(()),
+    }
+}
+
+
+/// The closest part of `dir` that exists, if it is not a directory.
+fn existing_non_directory(dir: &Path) -> Option<&Path> {
+    dir.ancestors().find(|p| p.exists()).filter(|p| !p.is_dir())
+}
+
+
+/// Create the parent directories of `path`, accessible by the owner only on Unix.
+///
+/// Fails with `ParentPathIsNotADirectory` if part of the path already exists as a file.
+pub fn create_parent_dirs(path: &str) -> Result<(), Error> {
+    let parent = match Path::new(path).parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => return Ok(()),
+    };
+
+    if let Some(existing) = existing_non_directory(parent) {
+        eprintln!("ERROR: {} exists but is not a directory, cannot create the state file under it.", existing.display());
+        return Err(Error::ParentPathIsNotADirectory);
+    }
+
+    let mut builder = fs::DirBuilder::new();
+    builder.recursive(true);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(0o700);
+    }
+
+    builder.create(parent).map_err(|_| Error::FailedToCreateDirectory)
+}
+
+
+/// Turn the terminal's echo on or off with `stty`, so a secret typed at a prompt is not shown.
+///
+/// Returns false when that did not work (not Unix, no `stty`, stdin is not a terminal), the input is then echoed as usual.
+pub fn set_terminal_echo(enabled: bool) -> bool {
+    if !cfg!(unix) {
+        return false;
+    }
+
+    std::process::Command::new("stty")
+        .arg(if enabled { "echo" } else { "-echo" })
+        .stdin(std::process::Stdio::inherit())
+        .stderr(std::process::Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+
+fn home_dir() -> Option<String> {
+    let var = if cfg!(windows) { "USERPROFILE" } else { "HOME" };
+
+    std::env::var(var).ok().filter(|h| !h.is_empty())
+}
+
+
+/// Advisory lock on `<state file>.lock`, held for as long as this value lives.
+///
+/// The OS drops the lock when the process exits, so a crash never leaves a stale lock behind.
+#[derive(Debug)]
+pub struct StateLock {
+    _file: File,
+}
+
+
+/// Take the single-instance lock for `state_file_path` (flock on Unix, LockFileEx on Windows).
+///
+/// Returns `StateFileLocked` without waiting if another process holds it.
+pub fn lock_state_file(state_file_path: &str) -> Result<StateLock, Error> {
+    let lock_path = format!("{}.lock", state_file_path);
+
+    let mut options = OpenOptions::new();
+    options.read(true).write(true).create(true).truncate(false);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let file = options
+        .open(&lock_path)
+        .map_err(|_| Error::FailedToCreateFile)?;
+
+    match file.try_lock() {
+        Ok(()) => Ok(StateLock { _file: file }),
+        Err(fs::TryLockError::WouldBlock) => Err(Error::StateFileLocked),
+        Err(fs::TryLockError::Error(_)) => Err(Error::FailedToLockFile),
+    }
+}
+
+
+/// Overwrite `path` with zeros, sync it and delete it, for `wipe`.
+///
+/// Best effort only: SSDs remap writes and copy-on-write filesystems (btrfs, ZFS, APFS) put the new bytes
+/// somewhere else, so the old blocks can survive. Full-disk encryption is what actually protects them.
+pub fn wipe_file(path: &str) -> Result<(), Error> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .open(path)
+        .map_err(|_| Error::FailedToOpenFile)?;
+
+    let len = file.metadata()
+        .map_err(|_| Error::FailedToGetFileMetadata)?
+        .len();
+
+    let zeros = [0u8; 64 * 1024];
+    let mut left = len;
+    while left > 0 {
+        let n = left.min(zeros.len() as u64) as usize;
+        file.write_all(&zeros[..n]).map_err(|_| Error::FailedToWriteToFile)?;
+        left -= n as u64;
+    }
+    file.sync_all().map_err(|_| Error::FailedToSyncFile)?;
+    drop(file);
+
+    fs::remove_file(path).map_err(|_| Error::FailedToDeleteFile)
+}
+
+
+/// Append-only `--log-file`, each line is prefixed with a UTC timestamp and a level.
+#[derive(Debug)]
+pub struct LogFile {
+    file: File,
+}
+
+
+impl LogFile {
+    /// Append one line. Logging is best effort, a full disk must not take the client down with it.
+    pub fn write_line(&self, level: &str, message: &str) {
+        let line = format!("{} {} {}\n", utc_timestamp(SystemTime::now()), level, message);
+        let _ = (&self.file).write_all(line.as_bytes());
+    }
+
+    /// Another handle to the same file, for writers that need to own one.
+    pub fn try_clone(&self) -> io::Result<File> {
+        self.file.try_clone()
+    }
+}
+
+
+/// Open `path` for appending, creating it (and its parent directories) as 0600 if missing.
+pub fn open_log_file(path: &str) -> Result<LogFile, Error> {
+    create_parent_dirs(path)?;
+
+    let mut options = OpenOptions::new();
+    options.append(true).create(true);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let file = options
+        .open(path)
+        .map_err(|_| Error::FailedToOpenLogFile)?;
+
+    Ok(LogFile { file })
+}
+
+
+/// Format `time` as an RFC 3339 UTC timestamp like "2024-05-01T13:37:00Z".
+pub fn utc_timestamp(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let (days, rem) = (secs / 86400, secs % 86400);
+
+    // civil date from days since 1970-01-01, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, rem / 3600, rem % 3600 / 60, rem % 60)
+}
+
+
+/// Parse an HTTP date in the IMF-fixdate form, e.g. "Sun, 06 Nov 1994 08:49:37 GMT" (RFC 9110 section 5.6.7).
+///
+/// The obsolete RFC 850 and asctime forms are not accepted, `None` for those and anything malformed.
+pub fn parse_http_date(value: &str) -> Option<SystemTime> {
+    let parts: Vec<&str> = value.split_ascii_whitespace().collect();
+    let [weekday, day, month, year, time, "GMT"] = parts.as_slice() else {
+        return None;
+    };
+    if !weekday.ends_with(',') || day.len() != 2 || year.len() != 4 {
+        return None;
+    }
+
+    let day: i64 = day.parse().ok()?;
+    let year: i64 = year.parse().ok()?;
+    let month = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"]
+        .iter()
+        .position(|m| m == month)? as i64 + 1;
+
+    let mut hms = time.split(':').map(|n| if n.len() == 2 { n.parse::<u64>().ok() } else { None });
+    let (Some(Some(hour)), Some(Some(minute)), Some(Some(second)), None) = (hms.next(), hms.next(), hms.next(), hms.next()) else {
+        return None;
+    };
+    if !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+
+    // days since 1970-01-01 from the civil date, the inverse of `utc_timestamp`
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = u64::try_from(era * 146097 + doe - 719468).ok()?;
+
+    Some(UNIX_EPOCH + std::time::Duration::from_secs(days * 86400 + hour * 3600 + minute * 60 + second))
+}
+
+
+/// Return the file's permission bits if group or others can access it (anything beyond 0600).
+///
+/// Always `None` on platforms without Unix permissions.
+pub fn overly_permissive_mode(path: &str) -> Result<Option<u32>, Error> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mode = fs::metadata(path)
+            .map_err(|_| Error::FailedToGetFileMetadata)?
+            .permissions()
+            .mode() & 0o777;
+
+        if mode & 0o077 != 0 {
+            return Ok(Some(mode));
+        }
+    }
+
+    #[cfg(not(unix))]
+    let _ = path;
+
+    Ok(None)
+}
+
+
+/// Write `bytes` to `path` so that a crash or power loss leaves either the old or the new file, never a mix.
+///
+/// The data goes to a temporary file in the same directory which is fsynced and then renamed over `path`
+/// (on Windows `fs::rename` replaces an existing target).
+pub fn write_state_atomic(path: &str, bytes: &[u8]) -> Result<(), Error> {
+    write_atomic_with(path, |file| file.write_all(bytes))
+}
+
+
+fn write_atomic_with<F: FnOnce(&mut File) -> io::Result<()>>(path: &str, write: F) -> Result<(), Error> {
+    let target = Path::new(path);
+    let dir = match target.parent() {
+        Some(d) if !d.as_os_str().is_empty() => d,
+        _ => Path::new("."),
+    };
+    let file_name = target.file_name()
+        .ok_or(Error::FailedToCreateFile)?
+        .to_string_lossy();
+
+    let tmp_path = dir.join(format!(".{}.tmp-{}", file_name, std::process::id()));
+
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+
+    // owner read/write only, regardless of the umask
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let mut file = options
+        .open(&tmp_path)
+        .map_err(|_| Error::FailedToCreateFile)?;
+
+    let result = write(&mut file)
+        .map_err(|_| Error::FailedToWriteToFile)
+        .and_then(|_| file.sync_all().map_err(|_| Error::FailedToSyncFile));
+
+    drop(file);
+
+    if let Err(e) = result.and_then(|_| fs::rename(&tmp_path, target).map_err(|_| Error::FailedToReplaceFile)) {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(e);
+    }
+
+    // persist the rename itself, directories can't be opened for syncing on Windows
+    #[cfg(unix)]
+    {
+        File::open(dir)
+            .and_then(|d| d.sync_all())
+            .map_err(|_| Error::FailedToSyncFile)?;
+    }
+
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("coldwire-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_expand_path() {
+        let home = home_dir().unwrap();
+        unsafe { std::env::set_var("COLDWIRE_TEST_EXPAND_DIR", "/srv/coldwire") };
+
+        assert_eq!(expand_path("~").unwrap(), home);
+        assert_eq!(expand_path("~/.config/coldwire/state").unwrap(), format!("{}/.config/coldwire/state", home));
+        assert_eq!(expand_path("$COLDWIRE_TEST_EXPAND_DIR/state").unwrap(), "/srv/coldwire/state");
+        assert_eq!(expand_path("${COLDWIRE_TEST_EXPAND_DIR}_backup/state").unwrap(), "/srv/coldwire_backup/state");
+        assert_eq!(expand_path("/tmp/a$/b~").unwrap(), "/tmp/a$/b~");
+
+        assert!(expand_path("$COLDWIRE_TEST_UNSET_VAR/state").unwrap_err().contains("COLDWIRE_TEST_UNSET_VAR is not set"));
+        assert!(expand_path("${COLDWIRE_TEST_EXPAND_DIR/state").unwrap_err().contains("unterminated"));
+        assert!(expand_path("~bob/state").unwrap_err().contains("not supported"));
+
+        unsafe { std::env::remove_var("COLDWIRE_TEST_EXPAND_DIR") };
+    }
+
+    #[cfg(all(unix, not(target_os = "macos")))]
+    #[test]
+    fn test_percent_encode_round_trip() {
+        assert_eq!(percent_encode("p/w@rd").as_str(), "p%2Fw%40rd");
+        assert_eq!(percent_encode("a b:c%d?#é").as_str(), "a%20b%3Ac%25d%3F%23%C3%A9");
+        assert_eq!(percent_encode("hunter2-._~!$&'()*+,;=").as_str(), "hunter2-._~!$&'()*+,;=");
+
+        for input in ["p/w@rd", "a b:c%d?#é", "\u{7f}tab\there", ""] {
+            assert_eq!(percent_decode(&percent_encode(input)).unwrap().as_str(), input);
+        }
+    }
+
+    #[test]
+    fn test_default_state_file_path() {
+        let home = home_dir().unwrap();
+
+        unsafe { std::env::set_var("XDG_STATE_HOME", "/custom/state") };
+        let with_xdg = default_state_file_path();
+        unsafe { std::env::remove_var("XDG_STATE_HOME") };
+        let without_xdg = default_state_file_path();
+
+        assert_eq!(with_xdg.as_deref(), Some("/custom/state/coldwire/state"));
+        assert_eq!(without_xdg, Some(format!("{}/.local/state/coldwire/state", home)));
+    }
+
+    #[cfg(all(unix, not(target_os = "macos")))]
+    #[test]
+    fn test_default_config_file_path() {
+        let home = home_dir().unwrap();
+
+        unsafe { std::env::set_var("XDG_CONFIG_HOME", "/custom/config") };
+        let with_xdg = default_config_file_path();
+        unsafe { std::env::remove_var("XDG_CONFIG_HOME") };
+        let without_xdg = default_config_file_path();
+
+        assert_eq!(with_xdg.as_deref(), Some("/custom/config/coldwire/config.toml"));
+        assert_eq!(without_xdg, Some(format!("{}/.config/coldwire/config.toml", home)));
+    }
+
+    #[test]
+    fn test_create_parent_dirs() {
+        let dir = temp_dir("parent-dirs");
+        let path = dir.join("a").join("b").join("state");
+
+        create_parent_dirs(path.to_str().unwrap()).unwrap();
+        assert!(dir.join("a").join("b").is_dir());
+        assert!(!path.exists());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(dir.join("a")).unwrap().permissions().mode() & 0o777, 0o700);
+        }
+
+        fs::write(dir.join("file"), b"").unwrap();
+        let result = create_parent_dirs(dir.join("file").join("sub").join("state").to_str().unwrap());
+        assert!(matches!(result, Err(Error::ParentPathIsNotADirectory)));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_lock_state_file() {
+        let dir = temp_dir("lock");
+        let path = dir.join("state");
+        let path_str = path.to_str().unwrap();
+
+        let lock = lock_state_file(path_str).unwrap();
+        assert!(matches!(lock_state_file(path_str), Err(Error::StateFileLocked)));
+
+        drop(lock);
+        assert!(lock_state_file(path_str).is_ok());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_check_profile_dir() {
+        let dir = temp_dir("profile-dir");
+        fs::write(dir.join("file"), b"").unwrap();
+
+        assert_eq!(check_profile_dir(dir.to_str().unwrap()), Ok(()));
+        assert_eq!(check_profile_dir(dir.join("new").join("work").to_str().unwrap()), Ok(()));
+        assert_eq!(check_profile_dir(dir.join("file").to_str().unwrap()), Err("points to a file, it must name a directory"));
+        assert_eq!(check_profile_dir(dir.join("file").join("work").to_str().unwrap()), Err("is under a file, not a directory"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_utc_timestamp() {
+        let at = |secs: u64| utc_timestamp(UNIX_EPOCH + std::time::Duration::from_secs(secs));
+
+        assert_eq!(at(0), "1970-01-01T00:00:00Z");
+        assert_eq!(at(951782400), "2000-02-29T00:00:00Z");
+        assert_eq!(at(1714570620), "2024-05-01T13:37:00Z");
+    }
+
+    #[test]
+    fn test_parse_http_date() {
+        let secs = |value: &str| parse_http_date(value).map(|t| t.duration_since(UNIX_EPOCH).unwrap().as_secs());
+
+        assert_eq!(secs("Sun, 06 Nov 1994 08:49:37 GMT"), Some(784111777));
+        assert_eq!(secs("Tue, 29 Feb 2000 00:00:00 GMT"), Some(951782400));
+        assert_eq!(secs("Wed, 01 May 2024 13:37:00 GMT"), Some(1714570620));
+
+        for bad in ["", "Sunday, 06-Nov-94 08:49:37 GMT", "Sun Nov  6 08:49:37 1994", "Sun, 06 Nov 1994 08:49:37 UTC",
+            "Sun, 06 Foo 1994 08:49:37 GMT", "Sun, 06 Nov 1994 8:49:37 GMT", "Sun, 06 Nov 1994 08:49 GMT", "Sun, 06 Nov 1969 08:49:37 GMT"] {
+            assert_eq!(secs(bad), None, "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_log_file_appends() {
+        let dir = temp_dir("log");
+        let path = dir.join("logs").join("coldwire.log");
+        let path_str = path.to_str().unwrap();
+
+        open_log_file(path_str).unwrap().write_line("INFO", "first");
+        open_log_file(path_str).unwrap().write_line("WARN", "second");
+
+        let content = fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].ends_with("Z INFO first"));
+        assert!(lines[1].ends_with("Z WARN second"));
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_check_state_file_path() {
+        let dir = temp_dir("check-state-path");
+        let dir_str = dir.to_str().unwrap();
+
+        assert_eq!(check_state_file_path(dir_str), Err("points to a directory"));
+        assert_eq!(check_state_file_path(&format!("{}/", dir_str)), Err("ends with a path separator, it must name a file"));
+        assert_eq!(check_state_file_path(&format!("{}/..", dir_str)), Err("points to a directory"));
+        assert_eq!(check_state_file_path("."), Err("points to a directory"));
+
+        let file = dir.join("state");
+        assert_eq!(check_state_file_path(file.to_str().unwrap()), Ok(()));
+        fs::write(&file, b"x").unwrap();
+        assert_eq!(check_state_file_path(file.to_str().unwrap()), Ok(()));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_write_state_atomic_replaces_file() {
+        let dir = temp_dir("atomic-replace");
+        let path = dir.join("state");
+        let path_str = path.to_str().unwrap();
+
+        write_state_atomic(path_str, b"first").unwrap();
+        write_state_atomic(path_str, b"second").unwrap();
+
+        assert_eq!(fs::read(&path).unwrap(), b"second");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1, "temporary file left behind");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_wipe_file() {
+        let dir = temp_dir("wipe");
+        let path = dir.join("state");
+        let path_str = path.to_str().unwrap();
+
+        fs::write(&path, vec![0xAA; 100_000]).unwrap();
+        wipe_file(path_str).unwrap();
+        assert!(!path.exists());
+
+        assert!(matches!(wipe_file(path_str), Err(Error::FailedToOpenFile)));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_state_file_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = temp_dir("perms");
+        let path = dir.join("state");
+        let path_str = path.to_str().unwrap();
+
+        write_state_atomic(path_str, b"state").unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert_eq!(overly_permissive_mode(path_str).unwrap(), None);
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        assert_eq!(overly_permissive_mode(path_str).unwrap(), Some(0o644));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_write_state_atomic_partial_write_keeps_old_file() {
+        let dir = temp_dir("atomic-partial");
+        let path = dir.join("state");
+        let path_str = path.to_str().unwrap();
+
+        write_state_atomic(path_str, b"old state").unwrap();
+
+        // simulate a crash halfway through writing the new state
+        let result = write_atomic_with(path_str, |file| {
+            file.write_all(b"new st")?;
+            Err(io::Error::other("simulated crash"))
+        });
+
+        assert!(matches!(result, Err(Error::FailedToWriteToFile)));
+        assert_eq!(fs::read(&path).unwrap(), b"old state");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1, "temporary file left behind");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}